@@ -0,0 +1,310 @@
+// Compare ways of streaming records as a json array: buffering the
+// whole response, ByteStream, and stream combinators that allocate a
+// buffer per record, as in example/src/widgets.rs.
+//
+// The number of rows and the size of each row can be set with the
+// BENCH_ROWS and BENCH_ROW_SIZE environment variables, as comma
+// separated lists. Besides the criterion timings, this prints the
+// allocations per row and per chunk, peak memory and time to first
+// chunk. A few chunks are held in flight, as when actix is writing
+// them to a socket, so a BytesMut cannot simply reuse its buffer.
+//
+// The "sqlx query" group compares the cost of a sqlx query of an
+// in-memory sqlite database through a SelfRefStream, which boxes its
+// args, with an UnboxedSelfRefStream.
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{
+    executor::block_on,
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use serde::Serialize;
+use sqlx_actix_streaming::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+// an allocator that counts allocations and tracks peak memory.
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Relaxed);
+        let current = CURRENT.fetch_add(layout.size(), Relaxed) + layout.size();
+        PEAK.fetch_max(current, Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Relaxed);
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Relaxed);
+        if new_size > layout.size() {
+            let current =
+                CURRENT.fetch_add(new_size - layout.size(), Relaxed) + new_size - layout.size();
+            PEAK.fetch_max(current, Relaxed);
+        } else {
+            CURRENT.fetch_sub(layout.size() - new_size, Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[derive(Serialize, Clone, Copy)]
+struct Record<'a> {
+    id: usize,
+    payload: &'a str,
+}
+
+type ChunkStream<'a> = BoxStream<'a, Result<Bytes, Error>>;
+
+// a synthetic stream of records, which does not allocate.
+fn records(
+    rows: usize,
+    payload: &str,
+) -> impl futures::Stream<Item = Result<Record<'_>, Error>> + Send {
+    stream::iter((0..rows).map(move |id| Ok(Record { id, payload })))
+}
+
+// fetch_all, then serialize the whole response.
+fn buffered(rows: usize, payload: &str) -> ChunkStream<'_> {
+    stream::once(async move {
+        let records: Vec<Record> = records(rows, payload).try_collect().await?;
+        Ok(Bytes::from(serde_json::to_vec(&records)?))
+    })
+    .boxed()
+}
+
+fn bytestream(rows: usize, payload: &str) -> ChunkStream<'_> {
+    ByteStream::new(
+        records(rows, payload),
+        |buf: &mut BytesWriter, rec: &Record| Ok(serde_json::to_writer(buf, rec)?),
+    )
+    .boxed()
+}
+
+// a pool shared by every benchmark iteration, as by every request.
+fn pool() -> BufferPool {
+    static POOL: OnceLock<BufferPool> = OnceLock::new();
+    POOL.get_or_init(|| BufferPool::new(64)).clone()
+}
+
+fn pooled(rows: usize, payload: &str) -> ChunkStream<'_> {
+    ByteStream::new(
+        records(rows, payload),
+        |buf: &mut BytesWriter, rec: &Record| Ok(serde_json::to_writer(buf, rec)?),
+    )
+    .pool(pool())
+    .boxed()
+}
+
+fn combinators(rows: usize, payload: &str) -> ChunkStream<'_> {
+    stream::once(future::ready(Ok(Bytes::from_static(b"["))))
+        .chain(records(rows, payload).enumerate().map(|(i, rec)| {
+            rec.and_then(|rec| {
+                let mut writer = BytesWriter(BytesMut::new());
+                if i > 0 {
+                    writer.0.put_u8(b',');
+                }
+                serde_json::to_writer(&mut writer, &rec)?;
+                Ok(writer.freeze())
+            })
+        }))
+        .chain(stream::once(future::ready(Ok(Bytes::from_static(b"]")))))
+        .boxed()
+}
+
+type Approach = for<'a> fn(usize, &'a str) -> ChunkStream<'a>;
+
+const APPROACHES: &[(&str, Approach)] = &[
+    ("buffered", buffered),
+    ("bytestream", bytestream),
+    ("pooled", pooled),
+    ("combinators", combinators),
+];
+
+// the number of chunks held until they are written.
+const IN_FLIGHT: usize = 4;
+
+struct Drained {
+    len: usize,
+    chunks: usize,
+    first_chunk: Duration,
+}
+
+// consume a stream, holding the last few chunks.
+fn drain(mut s: ChunkStream) -> Drained {
+    let start = Instant::now();
+    block_on(async {
+        let mut in_flight = VecDeque::with_capacity(IN_FLIGHT + 1);
+        let mut drained = Drained {
+            len: 0,
+            chunks: 0,
+            first_chunk: Duration::default(),
+        };
+        while let Some(chunk) = s.next().await {
+            if drained.chunks == 0 {
+                drained.first_chunk = start.elapsed();
+            }
+            let chunk = chunk.unwrap();
+            drained.len += chunk.len();
+            drained.chunks += 1;
+            in_flight.push_back(chunk);
+            if in_flight.len() > IN_FLIGHT {
+                in_flight.pop_front();
+            }
+        }
+        drained
+    })
+}
+
+fn env_list(name: &str, default: &[usize]) -> Vec<usize> {
+    std::env::var(name)
+        .ok()
+        .map(|s| s.split(',').filter_map(|n| n.trim().parse().ok()).collect())
+        .unwrap_or_else(|| default.to_vec())
+}
+
+fn configs() -> Vec<(usize, usize)> {
+    let mut configs = vec![];
+    for rows in env_list("BENCH_ROWS", &[1_000, 100_000]) {
+        for row_size in env_list("BENCH_ROW_SIZE", &[64, 1024]) {
+            configs.push((rows, row_size));
+        }
+    }
+    configs
+}
+
+// print the allocations per row, peak memory and time to first chunk.
+fn report() {
+    eprintln!(
+        "{:>12} {:>8} {:>8} {:>12} {:>12} {:>12} {:>14}",
+        "approach", "rows", "row size", "allocs/row", "allocs/chunk", "peak bytes", "first chunk"
+    );
+    for (rows, row_size) in configs() {
+        let payload = "x".repeat(row_size);
+        for (name, approach) in APPROACHES {
+            let allocs = ALLOCS.load(Relaxed);
+            let base = CURRENT.load(Relaxed);
+            PEAK.store(base, Relaxed);
+            let drained = drain(approach(rows, &payload));
+            let allocs = ALLOCS.load(Relaxed) - allocs;
+            let peak = PEAK.load(Relaxed) - base;
+            eprintln!(
+                "{:>12} {:>8} {:>8} {:>12.3} {:>12.3} {:>12} {:>14?}",
+                name,
+                rows,
+                row_size,
+                allocs as f64 / rows as f64,
+                allocs as f64 / drained.chunks as f64,
+                peak,
+                drained.first_chunk
+            );
+        }
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    report();
+    eprintln!("buffer pool: {:?}", pool().stats());
+    for (rows, row_size) in configs() {
+        let payload = "x".repeat(row_size);
+        let len = drain(bytestream(rows, &payload)).len;
+        let mut group = c.benchmark_group(format!("{} rows of {} bytes", rows, row_size));
+        group.throughput(Throughput::Bytes(len as u64));
+        if rows * row_size > 10_000_000 {
+            group.sample_size(10);
+        }
+        for (name, approach) in APPROACHES {
+            group.bench_with_input(BenchmarkId::from_parameter(name), &payload, |b, payload| {
+                b.iter(|| drain(approach(rows, payload)))
+            });
+        }
+        group.finish();
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Widget {
+    id: i64,
+    name: String,
+}
+
+// an in-memory sqlite database of the given number of widgets.
+async fn widgets_pool(rows: i64) -> sqlx::SqlitePool {
+    // each connection to :memory: is a separate database.
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE widgets (id INTEGER PRIMARY KEY NOT NULL, name TEXT NOT NULL)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < ?) \
+         INSERT INTO widgets SELECT id, 'widget ' || id FROM ids",
+    )
+    .bind(rows)
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool
+}
+
+// the query of the widgets, borrowing the pool owned by the stream.
+fn widget_rows(
+    pool: &sqlx::SqlitePool,
+) -> impl Stream<Item = Result<Widget, sqlx::Error>> + Send + '_ {
+    sqlx::query_as::<_, Widget>("SELECT id, name FROM widgets").fetch(pool)
+}
+
+// poll a stream of widgets to the end, returning the sum of their ids
+// and name lengths.
+async fn sum<S: Stream<Item = Result<Widget, sqlx::Error>>>(s: S) -> i64 {
+    s.try_fold(0, |sum, w| future::ok(sum + w.id + w.name.len() as i64))
+        .await
+        .unwrap()
+}
+
+fn bench_sqlx_query(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let rows = 10_000;
+    let pool = runtime.block_on(widgets_pool(rows));
+    let mut group = c.benchmark_group("sqlx query");
+    group.throughput(Throughput::Elements(rows as u64));
+    group.bench_function("SelfRefStream", |b| {
+        b.iter(|| {
+            runtime.block_on(sum(SelfRefStream::build(pool.clone(), |pool| {
+                sqlx::query_as::<_, Widget>("SELECT id, name FROM widgets").fetch(pool)
+            })))
+        })
+    });
+    group.bench_function("UnboxedSelfRefStream", |b| {
+        b.iter(|| runtime.block_on(sum(UnboxedSelfRefStream::build(pool.clone(), widget_rows))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench, bench_sqlx_query);
+criterion_main!(benches);
@@ -0,0 +1,190 @@
+use crate::widgets::{WidgetParams, WidgetRecord};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::*;
+use actix_web_actors::ws;
+use futures::{stream, StreamExt};
+use serde::*;
+use sqlx::postgres::*;
+use sqlx_actix_streaming::*;
+
+// The number of queries one socket may have open at once.
+const MAX_OPEN_QUERIES: usize = 4;
+
+// A message from the client: open a query, then ask for more rows, or
+// cancel it.
+//   {"query_id":"a","params":{"offset":0,"limit":1000}}
+//   {"query_id":"a","more":100}
+//   {"query_id":"a","cancel":true}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GridRequest {
+    Query { query_id: String, params: WidgetParams },
+    More { query_id: String, more: usize },
+    Cancel { query_id: String, cancel: bool },
+}
+
+// A message to the client: a row, the end of a query, or an error.
+#[derive(Serialize)]
+struct GridResponse<'a> {
+    query_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<&'a WidgetRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> GridResponse<'a> {
+    fn new(query_id: &'a str) -> Self {
+        Self {
+            query_id,
+            row: None,
+            done: None,
+            error: None,
+        }
+    }
+}
+
+// Each row of a query, and None at its end.
+struct GridRow(String, Option<Result<WidgetRecord, sqlx::Error>>);
+
+// A WebSocket that streams the rows of each query only as fast as the
+// client grants credit for them. Until then, the query is not polled,
+// and holds its cursor.
+struct GridSocket {
+    pool: PgPool,
+    queries: OpenQueries<String>,
+}
+
+impl GridSocket {
+    fn send(ctx: &mut ws::WebsocketContext<Self>, response: &GridResponse) {
+        if let Ok(text) = serde_json::to_string(response) {
+            ctx.text(text);
+        }
+    }
+    fn request(&mut self, request: GridRequest, ctx: &mut ws::WebsocketContext<Self>) {
+        match request {
+            GridRequest::Query { query_id, params } => {
+                let rows = SelfRefStream::build(
+                    (self.pool.clone(), params),
+                    move |(pool, params)| {
+                        sqlx::query_as!(
+                            WidgetRecord,
+                            "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2",
+                            params.limit,
+                            params.offset
+                        )
+                        .fetch(pool)
+                    },
+                );
+                match self.queries.open(query_id.clone(), rows) {
+                    Ok(rows) => {
+                        let id = query_id.clone();
+                        ctx.add_stream(
+                            rows.map(move |row| GridRow(id.clone(), Some(row)))
+                                .chain(stream::once(async move { GridRow(query_id, None) })),
+                        );
+                    }
+                    Err(e) => Self::send(
+                        ctx,
+                        &GridResponse {
+                            error: Some(e.to_string()),
+                            ..GridResponse::new(&query_id)
+                        },
+                    ),
+                }
+            }
+            GridRequest::More { query_id, more } => {
+                if !self.queries.grant(&query_id, more) {
+                    Self::send(
+                        ctx,
+                        &GridResponse {
+                            error: Some("no such query".to_string()),
+                            ..GridResponse::new(&query_id)
+                        },
+                    );
+                }
+            }
+            GridRequest::Cancel { query_id, cancel } => {
+                if cancel {
+                    self.queries.cancel(&query_id);
+                }
+            }
+        }
+    }
+}
+
+impl Actor for GridSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.queries.cancel_all();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GridSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(request) => self.request(request, ctx),
+                Err(e) => Self::send(
+                    ctx,
+                    &GridResponse {
+                        error: Some(e.to_string()),
+                        ..GridResponse::new("")
+                    },
+                ),
+            },
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+impl StreamHandler<GridRow> for GridSocket {
+    fn handle(&mut self, GridRow(query_id, row): GridRow, ctx: &mut Self::Context) {
+        let response = match &row {
+            Some(Ok(row)) => GridResponse {
+                row: Some(row),
+                ..GridResponse::new(&query_id)
+            },
+            Some(Err(e)) => GridResponse {
+                error: Some(e.to_string()),
+                ..GridResponse::new(&query_id)
+            },
+            None => GridResponse {
+                done: Some(true),
+                ..GridResponse::new(&query_id)
+            },
+        };
+        Self::send(ctx, &response);
+    }
+    // the end of one query does not end the socket.
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+#[get("/widgets_ws")]
+pub async fn widgets_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    ws::start(
+        GridSocket {
+            pool: pool.as_ref().clone(),
+            queries: OpenQueries::new(MAX_OPEN_QUERIES),
+        },
+        &req,
+        body,
+    )
+}
+
+pub fn service(cfg: &mut web::ServiceConfig) {
+    cfg.service(widgets_ws);
+}
@@ -3,6 +3,7 @@ use anyhow::Context;
 use log::*;
 use std::env;
 
+mod grid;
 mod widgets;
 
 type Db = sqlx::postgres::Postgres;
@@ -14,13 +15,35 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let pool = DbPool::connect(&env::var("DATABASE_URL").context("DATABASE_URL")?).await?;
     let pool = web::Data::new(pool); // avoid double Arc.
+    // 1 MB/s per client, across all of its downloads.
+    let limits = web::Data::new(sqlx_actix_streaming::RateLimits::<String>::new(
+        1_000_000,
+        64 * 1024,
+    ));
+    // streaming responses may hold at most 8 of the pool's connections.
+    let admission = web::Data::new(
+        sqlx_actix_streaming::Admission::new(8).queue_timeout(std::time::Duration::from_secs(2)),
+    );
+    // the reports sent in the last 5 minutes, up to 64 MB.
+    let cache = web::Data::new(
+        sqlx_actix_streaming::MemoryCache::new(64 << 20)
+            .max_age(std::time::Duration::from_secs(300)),
+    );
+    // the checkpoints of /widgets_resumable, from which a download is
+    // resumed.
+    let checkpoints = web::Data::new(sqlx_actix_streaming::Checkpoints::new());
     let addr = env::var("SOCKETADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
     info!("this web server is listening at http://{}", &addr);
     HttpServer::new(move || {
         actix_web::App::new()
             .wrap(middleware::Logger::default())
             .app_data(pool.clone())
+            .app_data(limits.clone())
+            .app_data(admission.clone())
+            .app_data(cache.clone())
+            .app_data(checkpoints.clone())
             .configure(widgets::service)
+            .configure(grid::service)
             .default_service(web::route().to(HttpResponse::NotFound))
     })
     .bind(&addr)
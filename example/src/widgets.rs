@@ -3,7 +3,7 @@ use actix_web::{
     web::{BufMut, BytesMut},
     *,
 };
-use futures::{future, stream, StreamExt};
+use futures::{future, stream, FutureExt, StreamExt};
 use serde::*;
 use sqlx::{postgres::*, prelude::*};
 use sqlx_actix_streaming::*;
@@ -16,20 +16,35 @@ pub struct WidgetRecord {
     pub description: String,
 }
 
+// the columns of the widgets table, in the order written by
+// to_writer_positional.
+const WIDGET_COLS: &[&str] = &["id", "serial", "name", "description"];
+
 #[derive(Deserialize, Serialize)]
 pub struct WidgetParams {
     pub offset: i64,
     pub limit: i64,
 }
 
+// Respond with 400 Bad Request if the query failed because of an
+// invalid parameter, such as a negative limit, otherwise with 500.
+fn query_error(e: sqlx::Error) -> actix_web::Error {
+    match &e {
+        sqlx::Error::Database(db) if db.code().map_or(false, |code| code.starts_with("22")) => {
+            ErrorBadRequest(e)
+        }
+        _ => ErrorInternalServerError(e),
+    }
+}
+
 #[post("/test")]
-pub async fn test(pool: web::Data<PgPool>) -> HttpResponse {
-    HttpResponse::Ok().content_type("application/json").json(
+pub async fn test(pool: web::Data<PgPool>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().content_type("application/json").json(
         sqlx::query_as!(WidgetRecord, "SELECT * FROM widgets",)
             .fetch_all(pool.as_ref())
             .await
-            .unwrap(),
-    )
+            .map_err(query_error)?,
+    ))
 }
 
 #[post("/testb")]
@@ -70,32 +85,33 @@ pub async fn widgets(
 pub async fn widgets2(
     web::Json(params): web::Json<WidgetParams>,
     pool: web::Data<PgPool>,
-) -> HttpResponse {
-    HttpResponse::Ok()
+) -> Result<HttpResponse> {
+    // a stream of WidgetRecords that owns pool and params, whose first
+    // record is fetched before the response status is chosen
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        // a stream of WidgetRecords that borrows pool and params
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
         .streaming(
             // a stream of text (Bytes) containing a JSON array of sqlx records
-            ByteStream::new(
-                // a stream of WidgetRecords that owns pool and params
-                SelfRefStream::build(
-                    (pool.as_ref().clone(), params),
-                    // a stream of WidgetRecords that borrows pool and params
-                    move |(pool, params)| {
-                        sqlx::query_as!(
-                            WidgetRecord,
-                            "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
-                            params.limit,
-                            params.offset
-                        )
-                        .fetch(pool)
-                    },
-                ),
-                |buf: &mut BytesWriter, record: &WidgetRecord| {
-                    // this writes a WidgetRecords as JSON text to the output buffer
-                    serde_json::to_writer(buf, record).map_err(ErrorInternalServerError)
-                },
-            ),
-        )
+            ByteStream::new(rows, |buf: &mut BytesWriter, record: &WidgetRecord| {
+                // this writes a WidgetRecords as JSON text to the output buffer
+                serde_json::to_writer(buf, record).map_err(ErrorInternalServerError)
+            }),
+        ))
 }
 
 // NOTE: this is the most efficient method. It does not clone strings.
@@ -111,61 +127,916 @@ pub struct WidgetRecordRef<'a> {
 pub async fn widgetsref(
     web::Json(params): web::Json<WidgetParams>,
     pool: web::Data<PgPool>,
-) -> HttpResponse {
-    HttpResponse::Ok()
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (
+            pool.as_ref().clone(),
+            "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+        ),
+        move |(pool, sql)| {
+            sqlx::query(sql)
+                .bind(params.limit)
+                .bind(params.offset)
+                .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .streaming(ByteStream::new(
-            SelfRefStream::build(
-                (
-                    pool.as_ref().clone(),
-                    "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
-                ),
-                move |(pool, sql)| {
-                    sqlx::query(sql)
-                        .bind(params.limit)
-                        .bind(params.offset)
+        .streaming(ByteStream::new(rows, |buf: &mut BytesWriter, row: &PgRow| {
+            serde_json::to_writer(
+                buf,
+                &WidgetRecordRef::from_row(row).map_err(ErrorInternalServerError)?,
+            )
+            .map_err(ErrorInternalServerError)
+        })))
+}
+
+#[post("/widget_table")]
+pub async fn widget_table(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(
+            ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+                to_writer_positional(buf, WIDGET_COLS, rec).map_err(ErrorInternalServerError)
+            })
+            // {"cols":[{"name":"id",...},...],"rows":[[1,10138,"spanner","..."],...]}
+            .field_columns(),
+        ))
+}
+
+// The columns, including their database types, are taken from the
+// first row, so an empty result fails with 404.
+#[post("/widget_rows")]
+pub async fn widget_rows(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (
+            pool.as_ref().clone(),
+            "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+        ),
+        move |(pool, sql)| {
+            sqlx::query(sql)
+                .bind(params.limit)
+                .bind(params.offset)
+                .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?
+    .non_empty(|| ErrorNotFound("no widgets"))?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, row: &PgRow| {
+            to_writer_positional(
+                buf,
+                WIDGET_COLS,
+                &WidgetRecordRef::from_row(row).map_err(ErrorInternalServerError)?,
+            )
+            .map_err(ErrorInternalServerError)
+        })
+        .row_columns(),
+    ))
+}
+
+#[post("/widgets_envelope")]
+pub async fn widgets_envelope(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let meta = serde_json::json!({ "offset": params.offset, "limit": params.limit });
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .envelope(&meta, |stats, last: Option<&WidgetRecord>| {
+            serde_json::json!({
+                "count": stats.item_count,
+                "next_cursor": last.map(|rec| rec.id),
+                "elapsed_ms": stats.elapsed_ms(),
+            })
+        })
+        .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct FieldsQuery {
+    pub fields: Option<String>,
+}
+
+// e.g. /widgets_fields?fields=name,id writes [{"name":"spanner","id":1},...].
+// The serial is not in the allowlist, so requesting it fails with 400.
+#[post("/widgets_fields")]
+pub async fn widgets_fields(
+    web::Query(query): web::Query<FieldsQuery>,
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let projection = FieldAllowlist::new()
+        .field("id")
+        .field("name")
+        .rename("description", "desc")
+        .project(query.fields.as_deref())
+        .map_err(ErrorBadRequest)?;
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, move |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            projection
+                .to_writer(buf, rec)
+                .map_err(ErrorInternalServerError)
+        }),
+    ))
+}
+
+fn widget_policies() -> RedactionPolicies {
+    RedactionPolicies::new(
+        RedactionPolicy::new()
+            .rule("serial", Redaction::Mask(2))
+            .rule("description", Redaction::Truncate(8)),
+    )
+    .role("admin", RedactionPolicy::new())
+}
+
+// The role of the authenticated user, which an authentication
+// middleware inserts into the request extensions, e.g. from a verified
+// session or token. It is never taken from a request header, which
+// any client could set. Without one, the default policy applies.
+#[derive(Clone, Default)]
+pub struct Role(pub String);
+
+impl FromRequest for Role {
+    type Error = actix_web::Error;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        future::ok(req.extensions().get::<Role>().cloned().unwrap_or_default())
+    }
+}
+
+// The serial and description are redacted unless the user is an admin.
+#[post("/widgets_redacted")]
+pub async fn widgets_redacted(
+    role: Role,
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let policy = widget_policies().for_role(&role.0).clone();
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, move |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            policy
+                .to_writer(buf, rec)
+                .map_err(ErrorInternalServerError)
+        }),
+    ))
+}
+
+// The count is awaited before the response starts, so an offset past
+// the last widget fails with 404 instead of streaming an empty array.
+#[post("/widgets_checked")]
+pub async fn widgets_checked(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = SelfRefStream::try_build_async(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            async move {
+                let count = sqlx::query_scalar!("SELECT count(*) FROM widgets")
+                    .fetch_one(pool)
+                    .await
+                    .map_err(query_error)?
+                    .unwrap_or_default();
+                if params.offset >= count {
+                    return Err(ErrorNotFound("offset is past the last widget"));
+                }
+                Ok(sqlx::query_as!(
+                    WidgetRecord,
+                    "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                    params.limit,
+                    params.offset
+                )
+                .fetch(pool))
+            }
+            .boxed_local()
+        },
+    )
+    .await?;
+    let rows = PeekedStream::new(rows).await.map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        }),
+    ))
+}
+
+// The rows are read from a repeatable read snapshot, and the
+// transaction is committed only after all of them are sent. If the
+// client disconnects first, it is rolled back.
+#[post("/widgets_tx")]
+pub async fn widgets_tx(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let mut tx = pool.begin().await.map_err(query_error)?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut tx)
+        .await
+        .map_err(query_error)?;
+    let rows = PeekedStream::new(
+        SelfRefStreamMut::build((tx, params), move |(tx, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(tx)
+        })
+        .on_end(|(tx, _params)| tx.commit().boxed()),
+    )
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        }),
+    ))
+}
+
+// actix-web does not send HTTP trailers, so the row count, checksum
+// and status are written at the end of the document instead:
+// {"data":[...],"trailers":{"X-Row-Count":3,"X-Content-SHA256":"...",...}}.
+// With a server that supports trailers, use http_trailers() instead.
+#[post("/widgets_export")]
+pub async fn widgets_export(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .embed_trailers(),
+    ))
+}
+
+// For a server that sends HTTP trailers, http_trailers() provides the
+// row count, checksum and status once the body ends. actix-web does
+// not send trailers, so this only logs them.
+#[post("/widgets_trailers")]
+pub async fn widgets_trailers(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    actix_web::rt::spawn(async move {
+        if let Ok(trailers) = receiver.await {
+            for (name, value) in trailers.headers().iter() {
+                log::info!("widgets_trailers: {}: {}", name, value);
+            }
+        }
+    });
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .http_trailers(sender),
+    ))
+}
+
+// An export that can be resumed after an interrupted download, e.g. by
+// `curl -C -`, which asks for the rest with `Range: bytes=N-`. The
+// server keeps the checkpoints of the export, and re-runs the query
+// for the widgets after the last one before byte N, skipping what the
+// client already has. The body is the same as a single-pass export.
+#[get("/widgets_resumable")]
+pub async fn widgets_resumable(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    checkpoints: web::Data<Checkpoints>,
+) -> Result<HttpResponse> {
+    let offset = match req.headers().get("Range") {
+        Some(range) => Some(
+            range
+                .to_str()
+                .ok()
+                .and_then(range_start)
+                .ok_or_else(|| ErrorRangeNotSatisfiable("only bytes=N- is supported"))?,
+        ),
+        None => None,
+    };
+    let checkpoint = offset
+        .and_then(|offset| checkpoints.before(offset))
+        .unwrap_or_default();
+    let after: i64 = match checkpoint.key.as_deref() {
+        Some(key) => key.parse().map_err(ErrorInternalServerError)?,
+        None => 0,
+    };
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), after),
+        move |(pool, after)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets WHERE id > $1 ORDER BY id",
+                after
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    let body = ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+        serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+    })
+    .checkpoints(|rec: &WidgetRecord| rec.id.to_string(), &checkpoints);
+    Ok(match offset {
+        Some(offset) => HttpResponse::PartialContent()
+            .content_type("application/json")
+            .insert_header(("Content-Range", content_range(offset)))
+            .streaming(body.resume_at(&checkpoint, offset)),
+        None => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(("Accept-Ranges", "bytes"))
+            .streaming(body),
+    })
+}
+
+// The widgets are merged from several shards, ordered by id, and a
+// shard that fails is reported in the summary. Here the shards are
+// simulated by partitioning one table, but each could own the pool of
+// a different database.
+#[post("/widgets_sharded")]
+pub async fn widgets_sharded(pool: web::Data<PgPool>) -> Result<HttpResponse> {
+    const SHARDS: i64 = 3;
+    let shards = (0..SHARDS)
+        .map(|shard| {
+            SelfRefStream::build((pool.as_ref().clone(), shard), move |(pool, shard)| {
+                sqlx::query_as!(
+                    WidgetRecord,
+                    "SELECT * FROM widgets WHERE id % $1 = $2 ORDER BY id",
+                    SHARDS,
+                    shard
+                )
+                .fetch(pool)
+            })
+        })
+        .collect();
+    let errors = ShardErrors::new();
+    let rows = MergeStream::ordered(shards, |rec: &WidgetRecord| rec.id)
+        .policy(ShardErrorPolicy::Report(errors.clone()));
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .envelope(&SHARDS, move |stats, _| {
+            serde_json::json!({ "count": stats.item_count, "errors": errors.to_vec() })
+        })
+        .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+#[derive(Serialize, FromRow)]
+pub struct WidgetTotals {
+    pub count: Option<i64>,
+    pub max_serial: Option<i64>,
+}
+
+// Several queries in one response:
+// {"totals":{"count":3,...},"top":[...],"names":[[1,"spanner"],...]}.
+// The later sections run while the first is being written.
+#[post("/widgets_dashboard")]
+pub async fn widgets_dashboard(pool: web::Data<PgPool>) -> HttpResponse {
+    let pool = pool.as_ref().clone();
+    HttpResponse::Ok().content_type("application/json").streaming(
+        Sections::new()
+            .section(
+                "totals",
+                ByteStream::new(
+                    SelfRefStream::build(pool.clone(), |pool| {
+                        sqlx::query_as!(
+                            WidgetTotals,
+                            "SELECT count(*) AS count, max(serial) AS max_serial FROM widgets"
+                        )
+                        .fetch(pool)
+                    }),
+                    |buf: &mut BytesWriter, rec: &WidgetTotals| {
+                        serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+                    },
+                )
+                .prefix("")
+                .suffix(""),
+            )
+            .section(
+                "top",
+                ByteStream::new(
+                    SelfRefStream::build(pool.clone(), |pool| {
+                        sqlx::query_as!(
+                            WidgetRecord,
+                            "SELECT * FROM widgets ORDER BY serial DESC LIMIT 5"
+                        )
                         .fetch(pool)
+                    }),
+                    |buf: &mut BytesWriter, rec: &WidgetRecord| {
+                        serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+                    },
+                ),
+            )
+            .section(
+                "names",
+                ByteStream::new(
+                    SelfRefStream::build(pool, |pool| {
+                        sqlx::query!("SELECT id, name FROM widgets ORDER BY id").fetch(pool)
+                    }),
+                    |buf: &mut BytesWriter, rec| {
+                        serde_json::to_writer(buf, &(rec.id, &rec.name))
+                            .map_err(ErrorInternalServerError)
+                    },
+                ),
+            )
+            .concurrency(3),
+    )
+}
+
+// A live feed of the widgets as server-sent events: the current
+// widgets, then each widget whose id is notified on the "widgets"
+// channel, e.g. by a trigger running pg_notify('widgets', NEW.id::text).
+// A heartbeat every 15 seconds keeps proxies from closing the
+// connection, and the subscription ends when the client goes away.
+#[get("/widgets_live")]
+pub async fn widgets_live(pool: web::Data<PgPool>) -> Result<HttpResponse> {
+    let rows = pool.as_ref().clone();
+    let events = PgLive::new(["widgets"])
+        .snapshot(SelfRefStream::build(rows.clone(), |pool| {
+            sqlx::query_as!(WidgetRecord, "SELECT * FROM widgets ORDER BY id").fetch(pool)
+        }))
+        .heartbeat(std::time::Duration::from_secs(15))
+        .listen(pool.as_ref(), move |notification: PgNotification| {
+            let pool = rows.clone();
+            async move {
+                let id = match notification.payload().parse::<i64>() {
+                    Ok(id) => id,
+                    Err(_) => return Ok(None),
+                };
+                sqlx::query_as!(WidgetRecord, "SELECT * FROM widgets WHERE id = $1", id)
+                    .fetch_optional(&pool)
+                    .await
+            }
+            .boxed()
+        })
+        .await
+        .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(
+            ByteStream::new(
+                events,
+                |buf: &mut BytesWriter, event: &LiveEvent<WidgetRecord>| {
+                    event
+                        .write_sse::<sqlx_actix_streaming::Error>(buf)
+                        .map_err(ErrorInternalServerError)
                 },
-            ),
-            |buf: &mut BytesWriter, row: &PgRow| {
-                serde_json::to_writer(
-                    buf,
-                    &WidgetRecordRef::from_row(row).map_err(ErrorInternalServerError)?,
+            )
+            .sse(),
+        ))
+}
+
+// The widgets read through a server-side cursor, 500 at a time, so
+// that the next batch is fetched only once the client has received the
+// previous one.
+#[post("/widgets_cursor")]
+pub async fn widgets_cursor(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(
+        PgCursor::new("SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2")
+            .bind(params.limit)
+            .bind(params.offset)
+            .batch_size(500)
+            .fetch::<WidgetRecord>(pool.as_ref()),
+    )
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        }),
+    ))
+}
+
+// An export limited to 1 MB/s per client, shared by all of the
+// client's downloads, where the client is named by the X-Client-Id
+// header. The query is read only as fast as the client may receive.
+#[post("/widgets_throttled")]
+pub async fn widgets_throttled(
+    req: HttpRequest,
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+    limits: web::Data<RateLimits<String>>,
+) -> Result<HttpResponse> {
+    let client = req
+        .headers()
+        .get("X-Client-Id")
+        .and_then(|id| id.to_str().ok())
+        .ok_or_else(|| ErrorBadRequest("X-Client-Id is required"))?;
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .throttle(limits.get(client.to_string())),
+    ))
+}
+
+// An export that waits for one of a limited number of permits before
+// taking a connection from the pool, and holds it until the response
+// ends, so that slow clients cannot starve the other endpoints. If no
+// permit is available within the queue timeout, it responds with 503
+// Service Unavailable and Retry-After.
+#[post("/widgets_admitted")]
+pub async fn widgets_admitted(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+    admission: web::Data<Admission>,
+) -> Result<HttpResponse> {
+    let permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", e.retry_after.as_secs().to_string()))
+                .body(e.to_string()))
+        }
+    };
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(
+            Admitted::new(rows, permit),
+            |buf: &mut BytesWriter, rec: &WidgetRecord| {
+                serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+            },
+        ),
+    ))
+}
+
+// The wait times and rejections of the streaming endpoints.
+#[get("/admission_stats")]
+pub async fn admission_stats(admission: web::Data<Admission>) -> HttpResponse {
+    let stats = admission.stats();
+    HttpResponse::Ok().json(serde_json::json!({
+        "admitted": stats.admitted,
+        "rejected": stats.rejected,
+        "in_use": stats.in_use,
+        "waiting": stats.waiting,
+        "total_wait_ms": stats.total_wait.as_millis() as u64,
+        "max_wait_ms": stats.max_wait.as_millis() as u64,
+    }))
+}
+
+// An export that gives up if the query returns no row within 5s,
+// stalls for 10s, or the client takes 30s to read a chunk, or if it
+// runs for more than 10 minutes. A timeout is written into the
+// document: {"data":[...],"error":{"code":"idle_timeout",...}}.
+#[post("/widgets_timeouts")]
+pub async fn widgets_timeouts(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    use std::time::Duration;
+    HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(
+            SelfRefStream::build((pool.as_ref().clone(), params), move |(pool, params)| {
+                sqlx::query_as!(
+                    WidgetRecord,
+                    "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                    params.limit,
+                    params.offset
                 )
-                .map_err(ErrorInternalServerError)
+                .fetch(pool)
+            }),
+            |buf: &mut BytesWriter, rec: &WidgetRecord| {
+                Ok::<_, sqlx_actix_streaming::Error>(serde_json::to_writer(buf, rec)?)
             },
-        ))
+        )
+        .prefix(r#"{"data":["#)
+        .suffix("]}")
+        .timeouts(
+            Timeouts::new()
+                .first_row(Duration::from_secs(5))
+                .idle(Duration::from_secs(10))
+                .client_gap(Duration::from_secs(30))
+                .total(Duration::from_secs(600)),
+        )
+        .on_error(|buf, e| {
+            let code = match e {
+                sqlx_actix_streaming::Error::Timeout(e) => e.kind.as_str(),
+                _ => "query_error",
+            };
+            write!(buf, r#"],"error":"#)?;
+            serde_json::to_writer(
+                &mut *buf,
+                &serde_json::json!({ "code": code, "message": e.to_string() }),
+            )?;
+            write!(buf, "}}")?;
+            Ok(())
+        }),
+    )
 }
 
-#[post("/widget_table")]
-pub async fn widget_table(
+// An export for slow clients: the query is read as fast as the
+// database returns it, into memory up to 1 MB and then into a
+// temporary file, so that its connection is released early. The
+// client is served from the buffer while the query has completed.
+#[post("/widgets_spilled")]
+pub async fn widgets_spilled(
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    let (body, mut driver) = spill(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        }),
+        1 << 20,
+    );
+    actix_web::rt::spawn(async move {
+        (&mut driver).await;
+        let stats = driver.stats();
+        log::info!(
+            "widgets_spilled: {} bytes spilled in {} chunks, peak memory {} bytes",
+            stats.spilled_bytes,
+            stats.spilled_chunks,
+            stats.peak_memory
+        );
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(body))
+}
+
+// An expensive report, cached in memory for 5 minutes once it has
+// been sent completely. A repeated request is served from the cache,
+// with its ETag, or with 304 Not Modified if the client has it. The
+// first response has no ETag, since it is known only at the end.
+#[post("/widgets_report")]
+pub async fn widgets_report(
+    req: HttpRequest,
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+    cache: web::Data<MemoryCache>,
+) -> Result<HttpResponse> {
+    let key = format!("widgets_report:{}:{}", params.offset, params.limit);
+    if let Some(cached) = cache.get(&key) {
+        let if_none_match = req
+            .headers()
+            .get("If-None-Match")
+            .and_then(|value| value.to_str().ok());
+        return Ok(match cached.respond(if_none_match) {
+            CachedResponse::NotModified { etag } => HttpResponse::NotModified()
+                .insert_header(("ETag", etag))
+                .finish(),
+            CachedResponse::Content { etag, body } => HttpResponse::Ok()
+                .content_type("application/json")
+                .insert_header(("ETag", etag))
+                .streaming(body),
+        });
+    }
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok().content_type("application/json").streaming(
+        ByteStream::new(rows, |buf: &mut BytesWriter, rec: &WidgetRecord| {
+            serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+        })
+        .tee(cache.sink(&key)),
+    ))
+}
+
+// The widgets, unless the client already has them: a cheap version
+// query, the row count and the newest transaction id, gives a weak
+// ETag, and a matching If-None-Match is answered with 304 Not Modified
+// without running the full query.
+#[post("/widgets_versioned")]
+pub async fn widgets_versioned(
+    req: HttpRequest,
+    web::Json(params): web::Json<WidgetParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse> {
+    let if_none_match = req
+        .headers()
+        .get("If-None-Match")
+        .and_then(|value| value.to_str().ok());
+    let version = conditional(if_none_match, &params, async {
+        let rec = sqlx::query!(
+            "SELECT count(*) AS count, max(xmin::text::bigint) AS version FROM widgets"
+        )
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(query_error)?;
+        Ok::<_, actix_web::Error>((rec.count, rec.version))
+    })
+    .await?;
+    let etag = match version {
+        Conditional::NotModified(etag) => {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(("ETag", etag.to_string()))
+                .finish())
+        }
+        Conditional::Modified(etag) => etag,
+    };
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(("ETag", etag.to_string()))
+        .streaming(ByteStream::new(
+            rows,
+            |buf: &mut BytesWriter, rec: &WidgetRecord| {
+                serde_json::to_writer(buf, rec).map_err(ErrorInternalServerError)
+            },
+        )))
+}
+
+// The widgets as an Excel workbook, zipped as it is streamed, with a
+// header row named after the fields of WidgetRecord.
+#[post("/widgets_xlsx")]
+pub async fn widgets_xlsx(
     web::Json(params): web::Json<WidgetParams>,
     pool: web::Data<PgPool>,
 ) -> HttpResponse {
     HttpResponse::Ok()
-        .content_type("application/json")
+        .content_type(XLSX_CONTENT_TYPE)
+        .insert_header((
+            "Content-Disposition",
+            content_disposition(&format!("widgets-{}.xlsx", params.offset)),
+        ))
         .streaming(
             ByteStream::new(
                 SelfRefStream::build((pool.as_ref().clone(), params), move |(pool, params)| {
                     sqlx::query_as!(
                         WidgetRecord,
-                        "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                        "SELECT * FROM widgets ORDER BY id LIMIT $1 OFFSET $2 ",
                         params.limit,
                         params.offset
                     )
                     .fetch(pool)
                 }),
                 |buf: &mut BytesWriter, rec: &WidgetRecord| {
-                    write!(
-                        &mut *buf,
-                        r#"[{}, {}, "{}", "{}"]"#,
-                        rec.id, rec.serial, rec.name, rec.description,
-                    )
-                    .map_err(ErrorInternalServerError)
+                    Ok::<_, sqlx_actix_streaming::Error>(write_xlsx_row(buf, rec)?)
                 },
             )
-            .prefix(r#"{"cols":["id", "serial", "name", "description"],"rows":["#)
-            .suffix(r#"]}"#),
+            .field_xlsx()
+            .sheet_name("Widgets"),
         )
 }
 
@@ -175,8 +1046,22 @@ pub async fn widget_table(
 pub async fn combinators(
     web::Json(params): web::Json<WidgetParams>,
     pool: web::Data<PgPool>,
-) -> HttpResponse {
-    HttpResponse::Ok()
+) -> Result<HttpResponse> {
+    let rows = PeekedStream::new(SelfRefStream::build(
+        (pool.as_ref().clone(), params),
+        move |(pool, params)| {
+            sqlx::query_as!(
+                WidgetRecord,
+                "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
+                params.limit,
+                params.offset
+            )
+            .fetch(pool)
+        },
+    ))
+    .await
+    .map_err(query_error)?;
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
         .streaming(
             stream::once(future::ready({
@@ -185,16 +1070,7 @@ pub async fn combinators(
                 Ok(b.freeze())
             }))
             .chain(
-                SelfRefStream::build((pool.as_ref().clone(), params), move |(pool, params)| {
-                    sqlx::query_as!(
-                        WidgetRecord,
-                        "SELECT * FROM widgets LIMIT $1 OFFSET $2 ",
-                        params.limit,
-                        params.offset
-                    )
-                    .fetch(pool)
-                })
-                .enumerate()
+                rows.enumerate()
                 .map(|(i, item)| {
                     item.map(|row| {
                         let mut writer = BytesWriter(BytesMut::new());
@@ -212,7 +1088,7 @@ pub async fn combinators(
                 b.put_u8(b']');
                 Ok(b.freeze())
             }))),
-        )
+        ))
 }
 
 pub fn service(cfg: &mut web::ServiceConfig) {
@@ -222,5 +1098,26 @@ pub fn service(cfg: &mut web::ServiceConfig) {
     cfg.service(widgets2);
     cfg.service(widgetsref);
     cfg.service(widget_table);
+    cfg.service(widget_rows);
+    cfg.service(widgets_envelope);
+    cfg.service(widgets_fields);
+    cfg.service(widgets_redacted);
+    cfg.service(widgets_checked);
+    cfg.service(widgets_tx);
+    cfg.service(widgets_export);
+    cfg.service(widgets_trailers);
+    cfg.service(widgets_resumable);
+    cfg.service(widgets_sharded);
+    cfg.service(widgets_dashboard);
+    cfg.service(widgets_live);
+    cfg.service(widgets_cursor);
+    cfg.service(widgets_throttled);
+    cfg.service(widgets_admitted);
+    cfg.service(admission_stats);
+    cfg.service(widgets_timeouts);
+    cfg.service(widgets_spilled);
+    cfg.service(widgets_report);
+    cfg.service(widgets_versioned);
+    cfg.service(widgets_xlsx);
     cfg.service(combinators);
 }
@@ -4,3 +4,24 @@ curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http:/
 # curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widget_table |jq
 # curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgetrows |jq
 # curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgetrows2 |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_envelope |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' 'http://localhost:8080/widgets_fields?fields=name,id' |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_redacted |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_checked |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_tx |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_export |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_trailers |jq
+# curl -s -C - -o widgets.json http://localhost:8080/widgets_resumable
+# curl -s -X POST http://localhost:8080/widgets_sharded |jq
+# curl -s -X POST http://localhost:8080/widgets_dashboard |jq
+# curl -s -N http://localhost:8080/widgets_live
+# (echo '{"query_id":"a","params":{"offset":0,"limit":100}}'; echo '{"query_id":"a","more":10}'; sleep 1) |websocat ws://localhost:8080/widgets_ws
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_cursor |jq
+# curl -s -H 'X-Client-Id: partner' -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_throttled |jq
+# curl -s -i -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_admitted
+# curl -s http://localhost:8080/admission_stats |jq
+# curl -s -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_timeouts |jq
+# curl -s --limit-rate 10k -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_spilled |jq
+# curl -s -i -H 'If-None-Match: "etag"' -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_report
+# curl -s -i -H 'If-None-Match: W/"etag"' -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_versioned
+# curl -s -o widgets.xlsx -H 'Content-Type: application/json' -d '{"offset":0,"limit":100}' http://localhost:8080/widgets_xlsx
@@ -0,0 +1,238 @@
+use futures::{
+    future::{self, Either},
+    task::{Context, Poll, Waker},
+    Future, Stream,
+};
+use futures_timer::Delay;
+#[cfg(feature = "log")]
+use log::*;
+use pin_project::pin_project;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The counters of an Admission.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+    /// Number of streams admitted.
+    pub admitted: u64,
+    /// Number of streams that timed out in the queue.
+    pub rejected: u64,
+    /// Number of permits held now.
+    pub in_use: usize,
+    /// Number of streams waiting now.
+    pub waiting: usize,
+    /// Total time spent waiting by the admitted streams.
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+#[derive(Debug)]
+struct AdmissionState {
+    available: usize,
+    // the waiting streams, first come first served.
+    waiters: VecDeque<(u64, Waker)>,
+    // the waiters that were handed a permit, but not yet polled.
+    granted: HashSet<u64>,
+    next_id: u64,
+    stats: AdmissionStats,
+}
+
+impl AdmissionState {
+    // hand the permit to the first waiter, if any.
+    fn release(&mut self) {
+        match self.waiters.pop_front() {
+            Some((id, waker)) => {
+                self.granted.insert(id);
+                waker.wake();
+            }
+            None => self.available += 1,
+        }
+    }
+}
+
+/// The error when no permit became available within the queue timeout,
+/// e.g. to respond with 503 Service Unavailable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overloaded {
+    /// When the client might retry, for a Retry-After header.
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "overloaded, retry after {} seconds",
+            self.retry_after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// A budget of permits for streaming responses, separate from the
+/// connection pool, so that slow clients holding connections for the
+/// duration of their responses cannot starve the other endpoints. Set
+/// it to fewer than the pool's max connections.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    state: Arc<Mutex<AdmissionState>>,
+    queue_timeout: Duration,
+}
+
+impl Admission {
+    /// Allow `permits` concurrent streams. The queue timeout is 5s by
+    /// default.
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(AdmissionState {
+                available: permits,
+                waiters: VecDeque::new(),
+                granted: HashSet::new(),
+                next_id: 0,
+                stats: AdmissionStats::default(),
+            })),
+            queue_timeout: Duration::from_secs(5),
+        }
+    }
+    /// How long to wait for a permit before failing with Overloaded.
+    #[inline]
+    pub fn queue_timeout(mut self, queue_timeout: Duration) -> Self {
+        self.queue_timeout = queue_timeout;
+        self
+    }
+    /// Wait for a permit, which is released when it is dropped.
+    pub async fn admit(&self) -> Result<Permit, Overloaded> {
+        let started = Instant::now();
+        let acquire = Acquire {
+            admission: self.clone(),
+            id: None,
+        };
+        match future::select(acquire, Delay::new(self.queue_timeout)).await {
+            Either::Left((permit, _)) => {
+                let waited = started.elapsed();
+                let mut state = self.state.lock().unwrap();
+                state.stats.admitted += 1;
+                state.stats.total_wait += waited;
+                state.stats.max_wait = state.stats.max_wait.max(waited);
+                Ok(permit)
+            }
+            Either::Right(((), acquire)) => {
+                drop(acquire);
+                self.state.lock().unwrap().stats.rejected += 1;
+                #[cfg(feature = "log")]
+                warn!("no permit available after {:?}", self.queue_timeout);
+                Err(Overloaded {
+                    retry_after: self.queue_timeout.max(Duration::from_secs(1)),
+                })
+            }
+        }
+    }
+    pub fn stats(&self) -> AdmissionStats {
+        let state = self.state.lock().unwrap();
+        AdmissionStats {
+            waiting: state.waiters.len(),
+            ..state.stats.clone()
+        }
+    }
+}
+
+// waits in the queue of an Admission.
+struct Acquire {
+    admission: Admission,
+    // once queued.
+    id: Option<u64>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut state = this.admission.state.lock().unwrap();
+        match this.id {
+            Some(id) if state.granted.remove(&id) => this.id = None,
+            Some(id) => {
+                if let Some(waiter) = state.waiters.iter_mut().find(|(i, _)| *i == id) {
+                    waiter.1 = cx.waker().clone();
+                }
+                return Poll::Pending;
+            }
+            None if state.available > 0 && state.waiters.is_empty() => state.available -= 1,
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                this.id = Some(id);
+                return Poll::Pending;
+            }
+        }
+        state.stats.in_use += 1;
+        Poll::Ready(Permit {
+            admission: this.admission.clone(),
+        })
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.admission.state.lock().unwrap();
+            if state.granted.remove(&id) {
+                state.release();
+            } else {
+                state.waiters.retain(|(i, _)| *i != id);
+            }
+        }
+    }
+}
+
+/// A permit of an Admission, released when it is dropped.
+#[derive(Debug)]
+pub struct Permit {
+    admission: Admission,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.stats.in_use -= 1;
+        state.release();
+    }
+}
+
+/// A stream that holds a permit until it ends or is dropped.
+#[pin_project]
+pub struct Admitted<S> {
+    #[pin]
+    inner_stream: S,
+    permit: Option<Permit>,
+}
+
+impl<S> Admitted<S> {
+    pub fn new(inner_stream: S, permit: Permit) -> Self {
+        Self {
+            inner_stream,
+            permit: Some(permit),
+        }
+    }
+}
+
+impl<S: Stream> Stream for Admitted<S> {
+    type Item = S::Item;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let poll = this.inner_stream.poll_next(cx);
+        if let Poll::Ready(None) = poll {
+            *this.permit = None;
+        }
+        poll
+    }
+}
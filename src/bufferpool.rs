@@ -0,0 +1,124 @@
+use bytes::{Bytes, BytesMut};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::Relaxed},
+    Arc, Mutex, Weak,
+};
+
+/// A shared, bounded pool of output buffers. Buffers are grouped in
+/// classes by capacity, a power of two. A buffer frozen by the pool
+/// is returned to it when the last reference to its Bytes is dropped,
+/// e.g. after actix has written it to the socket. Cloning is cheap.
+#[derive(Clone)]
+pub struct BufferPool(Arc<PoolInner>);
+
+struct PoolInner {
+    // the free buffers of each class, indexed by log2 of the capacity.
+    classes: Mutex<Vec<Vec<BytesMut>>>,
+    max_per_class: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    returned: AtomicUsize,
+    discarded: AtomicUsize,
+}
+
+/// Counts of buffer pool operations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers taken from the pool.
+    pub hits: usize,
+    /// Buffers allocated because the pool had none of the class.
+    pub misses: usize,
+    /// Buffers returned to the pool.
+    pub returned: usize,
+    /// Buffers freed because the pool was full.
+    pub discarded: usize,
+}
+
+// the class of buffers that can hold at least the given capacity.
+#[inline]
+fn class_of(capacity: usize) -> usize {
+    capacity.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BufferPool {
+    /// Create a pool holding at most the given number of free buffers
+    /// per class.
+    pub fn new(max_per_class: usize) -> Self {
+        BufferPool(Arc::new(PoolInner {
+            classes: Mutex::new(vec![Vec::new(); usize::BITS as usize]),
+            max_per_class,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            returned: AtomicUsize::new(0),
+            discarded: AtomicUsize::new(0),
+        }))
+    }
+    /// Take an empty buffer having at least the given capacity.
+    pub fn get(&self, capacity: usize) -> BytesMut {
+        let class = class_of(capacity);
+        let buf = self.0.classes.lock().unwrap()[class].pop();
+        match buf {
+            Some(buf) => {
+                self.0.hits.fetch_add(1, Relaxed);
+                buf
+            }
+            None => {
+                self.0.misses.fetch_add(1, Relaxed);
+                BytesMut::with_capacity(1 << class)
+            }
+        }
+    }
+    /// Freeze a buffer into Bytes that return it to the pool when
+    /// dropped.
+    #[inline]
+    pub fn freeze(&self, buf: BytesMut) -> Bytes {
+        Bytes::from_owner(Pooled {
+            buf,
+            pool: Arc::downgrade(&self.0),
+        })
+    }
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.0.hits.load(Relaxed),
+            misses: self.0.misses.load(Relaxed),
+            returned: self.0.returned.load(Relaxed),
+            discarded: self.0.discarded.load(Relaxed),
+        }
+    }
+}
+
+impl PoolInner {
+    fn put(&self, mut buf: BytesMut) {
+        // the largest class the buffer can serve.
+        let class = (usize::BITS - 1 - buf.capacity().max(1).leading_zeros()) as usize;
+        let mut classes = self.classes.lock().unwrap();
+        if classes[class].len() < self.max_per_class {
+            buf.clear();
+            classes[class].push(buf);
+            self.returned.fetch_add(1, Relaxed);
+        } else {
+            self.discarded.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+// the owner of the buffer of a frozen Bytes.
+struct Pooled {
+    buf: BytesMut,
+    pool: Weak<PoolInner>,
+}
+
+impl AsRef<[u8]> for Pooled {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
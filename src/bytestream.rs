@@ -1,12 +1,29 @@
+use crate::{
+    timeout::TimeoutState,
+    trailers::{embedded_trailers, EMBEDDED_PREFIX, EMBEDDED_TRAILERS},
+    util::to_hex,
+    BufferPool, Checkpoint, Checkpoints, HttpTrailers, StreamStatus,
+};
 use bytes::{Bytes, BytesMut};
 use futures::{
+    channel::oneshot,
     task::{Context, Poll},
     Stream, TryStream,
 };
 #[cfg(feature = "log")]
 use log::*;
+use pin_project::{pin_project, pinned_drop};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 pub use std::io::Write;
-use std::pin::Pin;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 pub struct BytesWriter(pub BytesMut);
 impl BytesWriter {
@@ -49,8 +66,51 @@ pub enum State {
     Done,
 }
 
+/// Running statistics of a ByteStream, passed to the trailer.
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    /// Number of items received from the inner stream.
+    pub item_count: usize,
+    /// Number of bytes written to the output so far.
+    pub byte_count: usize,
+    /// When the stream was first polled.
+    pub started: Instant,
+    /// The hex SHA-256 of the output so far, if a checksum is enabled.
+    pub sha256: Option<String>,
+}
+
+impl StreamStats {
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+    #[inline]
+    pub fn elapsed_ms(&self) -> u64 {
+        self.elapsed().as_millis() as u64
+    }
+}
+
+/// A closure that writes the preamble, after the prefix, given the
+/// first item, or None if the stream is empty.
+pub type Preamble<Item, OuterError> =
+    Box<dyn FnMut(&mut BytesWriter, Option<&Item>) -> Result<(), OuterError> + Send>;
+
+/// A closure that writes the trailer, after the suffix, given the
+/// final statistics and the last item.
+pub type Trailer<Item, OuterError> =
+    Box<dyn FnMut(&mut BytesWriter, &StreamStats, Option<&Item>) -> Result<(), OuterError> + Send>;
+
+/// A closure that returns the key of an item, for a Checkpoint.
+pub type CursorKey<Item> = Box<dyn FnMut(&Item) -> String + Send>;
+
+/// A closure that writes an error, and the end of the document, in
+/// place of failing the stream.
+pub type OnError<OuterError> =
+    Box<dyn FnMut(&mut BytesWriter, &OuterError) -> Result<(), OuterError> + Send>;
+
 const BYTESTREAM_DEFAULT_ITEM_SIZE: usize = 2048;
 
+#[pin_project(PinnedDrop, project = ByteStreamProj)]
 pub struct ByteStream<InnerStream, InnerError, Serializer, OuterError>
 where
     InnerError: std::error::Error,
@@ -59,16 +119,42 @@ where
     Serializer:
         FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
 {
-    inner_stream: Pin<Box<InnerStream>>,
+    // stored inline, and pinned in place once polled. None once a
+    // timeout expires, to release the query at once.
+    #[pin]
+    inner_stream: Option<InnerStream>,
     serializer: Serializer,
     state: State,
     item_size: usize,
+    // the capacity of a new output buffer.
+    buf_size: usize,
+    pool: Option<BufferPool>,
     prefix: Vec<u8>,
     delimiter: Vec<u8>,
     suffix: Vec<u8>,
+    preamble: Option<Preamble<<InnerStream as TryStream>::Ok, OuterError>>,
+    trailer: Option<Trailer<<InnerStream as TryStream>::Ok, OuterError>>,
+    // the last item, retained only when there is a trailer.
+    last_item: Option<Box<<InnerStream as TryStream>::Ok>>,
     buf: BytesWriter,
-    #[cfg(feature = "log")]
-    item_count: usize,
+    pub(crate) stats: StreamStats,
+    // the running digest of every emitted chunk, if enabled.
+    checksum: Option<Sha256>,
+    http_trailers: Option<oneshot::Sender<HttpTrailers>>,
+    // whether to wrap the document, and write the trailers at its end.
+    pub(crate) embedded_trailers: bool,
+    on_error: Option<OnError<OuterError>>,
+    // set when on_error ends the stream, e.g. for a Tee to abort.
+    pub(crate) failed: Option<Arc<AtomicBool>>,
+    pub(crate) timeouts: Option<TimeoutState<OuterError>>,
+    // the key of the last item, and where to record each checkpoint.
+    pub(crate) cursor: Option<(CursorKey<<InnerStream as TryStream>::Ok>, Checkpoints)>,
+    // the bytes of resumed output not to send, up to the requested offset.
+    pub(crate) skip: usize,
+    // the initial state when resuming after a checkpoint.
+    pub(crate) resumed: Option<State>,
+    // whether to warn when dropped before done; off when the owner warns.
+    pub(crate) warn_on_drop: bool,
 }
 
 impl<InnerStream, InnerError, Serializer, OuterError>
@@ -86,16 +172,36 @@ where
     }
     pub fn with_size(inner_stream: InnerStream, serializer: Serializer, size: usize) -> Self {
         Self {
-            inner_stream: Box::pin(inner_stream),
+            inner_stream: Some(inner_stream),
             serializer,
             state: State::Unused,
             item_size: size,
+            buf_size: size,
+            pool: None,
             prefix: vec![b'['],
             delimiter: vec![b','],
             suffix: vec![b']'],
-            buf: BytesWriter(BytesMut::with_capacity(size)),
-            #[cfg(feature = "log")]
-            item_count: 0,
+            preamble: None,
+            trailer: None,
+            last_item: None,
+            // allocated when first written, from the pool if any.
+            buf: BytesWriter(BytesMut::new()),
+            stats: StreamStats {
+                item_count: 0,
+                byte_count: 0,
+                started: Instant::now(),
+                sha256: None,
+            },
+            checksum: None,
+            http_trailers: None,
+            embedded_trailers: false,
+            on_error: None,
+            failed: None,
+            timeouts: None,
+            cursor: None,
+            skip: 0,
+            resumed: None,
+            warn_on_drop: true,
         }
     }
     /// Set the prefix for the json array. '[' by default.
@@ -116,33 +222,271 @@ where
         self.suffix = s.to_string().into_bytes();
         self
     }
+    /// Take the output buffers from a pool, and return each to the pool
+    /// once the consumer has dropped its chunk.
+    #[inline]
+    pub fn pool(mut self, pool: BufferPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+    /// Compute a SHA-256 of the output, which is passed to the trailer
+    /// in StreamStats::sha256.
+    #[inline]
+    pub fn checksum(mut self) -> Self {
+        self.checksum.get_or_insert_with(Sha256::new);
+        self
+    }
+    /// Send the final row count, checksum and status, once the stream
+    /// is done, fails or is dropped, so that they can be sent as HTTP
+    /// trailers by a server that supports them.
+    #[inline]
+    pub fn http_trailers(mut self, sender: oneshot::Sender<HttpTrailers>) -> Self {
+        self.http_trailers = Some(sender);
+        self.checksum()
+    }
+    /// Set a closure that writes an error into the document, e.g.
+    /// `],"error":"..."}` after the items, instead of failing the
+    /// stream, which clients would see as a truncated response.
+    #[inline]
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(&mut BytesWriter, &OuterError) -> Result<(), OuterError> + Send + 'static,
+    {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+    /// Set a closure that writes a preamble after the prefix. Both are
+    /// deferred until the first item is available, so that the
+    /// preamble can describe it, e.g. its column names.
+    #[inline]
+    pub fn preamble<F>(mut self, preamble: F) -> Self
+    where
+        F: FnMut(
+                &mut BytesWriter,
+                Option<&<InnerStream as TryStream>::Ok>,
+            ) -> Result<(), OuterError>
+            + Send
+            + 'static,
+    {
+        self.preamble = Some(Box::new(preamble));
+        self
+    }
+    /// Set a closure that writes a trailer after the suffix, once the
+    /// inner stream is done. It receives the final statistics and the
+    /// last item, e.g. to write a pagination cursor.
+    #[inline]
+    pub fn trailer<F>(mut self, trailer: F) -> Self
+    where
+        F: FnMut(
+                &mut BytesWriter,
+                &StreamStats,
+                Option<&<InnerStream as TryStream>::Ok>,
+            ) -> Result<(), OuterError>
+            + Send
+            + 'static,
+    {
+        self.trailer = Some(Box::new(trailer));
+        self
+    }
+    /// Wrap the json array in an object having a metadata header and
+    /// a summary, which is rendered when the inner stream is done:
+    /// `{"meta":header,"data":[...],"summary":summary(stats, last_item)}`.
+    pub fn envelope<Header, Summary, F>(
+        mut self,
+        header: &Header,
+        mut summary: F,
+    ) -> Result<Self, serde_json::Error>
+    where
+        Header: Serialize + ?Sized,
+        Summary: Serialize,
+        F: FnMut(&StreamStats, Option<&<InnerStream as TryStream>::Ok>) -> Summary + Send + 'static,
+        OuterError: From<serde_json::Error>,
+    {
+        let mut prefix = br#"{"meta":"#.to_vec();
+        serde_json::to_writer(&mut prefix, header)?;
+        prefix.extend_from_slice(br#","data":["#);
+        self.prefix = prefix;
+        self.suffix = br#"],"summary":"#.to_vec();
+        Ok(self.trailer(move |buf, stats, last_item| {
+            serde_json::to_writer(&mut *buf, &summary(stats, last_item))?;
+            buf.0.extend_from_slice(b"}");
+            Ok(())
+        }))
+    }
+}
+
+// the helpers borrow the projected fields, leaving inner_stream pinned.
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStreamProj<'_, InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
     // append the configured prefix to the output buffer.
     #[inline]
     fn put_prefix(&mut self) {
-        self.buf.0.extend_from_slice(&self.prefix);
+        if *self.embedded_trailers {
+            self.buf.0.extend_from_slice(EMBEDDED_PREFIX);
+        }
+        self.buf.0.extend_from_slice(self.prefix);
     }
     // append the configured delimiter to the output buffer.
     #[inline]
     fn put_delimiter(&mut self) {
-        self.buf.0.extend_from_slice(&self.delimiter);
+        self.buf.0.extend_from_slice(self.delimiter);
     }
     // append the configured suffix to the output buffer.
     #[inline]
     fn put_suffix(&mut self) {
-        self.buf.0.extend_from_slice(&self.suffix);
+        self.buf.0.extend_from_slice(self.suffix);
+    }
+    // append the deferred prefix and the preamble, if any, given the first item.
+    #[inline]
+    fn put_preamble(
+        &mut self,
+        first: Option<&<InnerStream as TryStream>::Ok>,
+    ) -> Result<(), OuterError> {
+        if let Some(mut preamble) = self.preamble.take() {
+            self.put_prefix();
+            preamble(self.buf, first)?;
+        }
+        Ok(())
+    }
+    // use the trailer, if any, to write the final statistics and last
+    // item, followed by the embedded trailers, if any.
+    #[inline]
+    fn put_trailer(&mut self) -> Result<(), OuterError> {
+        if let Some(trailer) = self.trailer.as_mut() {
+            let stats = final_stats(self.stats, self.checksum.as_ref(), self.buf);
+            trailer(self.buf, &stats, self.last_item.as_deref())?;
+        }
+        if *self.embedded_trailers {
+            self.buf.0.extend_from_slice(EMBEDDED_TRAILERS);
+            let stats = final_stats(self.stats, self.checksum.as_ref(), self.buf);
+            self.buf
+                .0
+                .extend_from_slice(embedded_trailers(&stats).as_bytes());
+        }
+        Ok(())
+    }
+    // send the http trailers, if requested and not already sent.
+    fn send_http_trailers(&mut self, status: StreamStatus) {
+        if let Some(sender) = self.http_trailers.take() {
+            let sha256 = self.checksum.take().unwrap_or_default().finalize();
+            sender
+                .send(HttpTrailers {
+                    row_count: self.stats.item_count,
+                    sha256: to_hex(&sha256),
+                    status,
+                    resume_token: self
+                        .cursor
+                        .as_ref()
+                        .and_then(|(_, checkpoints)| checkpoints.latest())
+                        .map(|checkpoint| checkpoint.to_string()),
+                })
+                .ok();
+        }
     }
     // return the buffered output bytes.
     #[inline]
     fn bytes(&mut self) -> Bytes {
-        self.buf.0.split().freeze()
+        // the chunk ends after an item, unless it has the suffix.
+        if let (Some((key, checkpoints)), false) =
+            (self.cursor.as_mut(), matches!(*self.state, State::Done))
+        {
+            let checkpoint = Checkpoint {
+                offset: self.stats.byte_count + self.buf.0.len(),
+                item_count: self.stats.item_count,
+                key: self.last_item.as_deref().map(key),
+            };
+            checkpoints.record(checkpoint);
+        }
+        self.stats.byte_count += self.buf.0.len();
+        // resumed output starts at the requested offset.
+        let skip = (*self.skip).min(self.buf.0.len());
+        *self.skip -= skip;
+        if let Some(checksum) = self.checksum.as_mut() {
+            checksum.update(&self.buf.0[skip..]);
+        }
+        let bytes = match self.pool.as_ref() {
+            // the next buffer is taken when it is written.
+            Some(pool) => pool.freeze(std::mem::take(&mut self.buf.0)),
+            None => self.buf.0.split().freeze(),
+        };
+        match skip {
+            0 => bytes,
+            _ => bytes.slice(skip..),
+        }
+    }
+    // write the error into the document, if there is an error writer,
+    // and end the stream.
+    fn put_error(&mut self, e: OuterError) -> Poll<Option<Result<Bytes, OuterError>>> {
+        if self.on_error.is_none() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        *self.state = State::Done;
+        if let Some(failed) = self.failed.as_ref() {
+            failed.store(true, Ordering::Release);
+        }
+        // the prefix and preamble are still deferred if no item arrived.
+        if let Err(e) = self.put_preamble(None) {
+            #[cfg(feature = "log")]
+            error!("failed to write preamble: {:?}", e);
+            return Poll::Ready(Some(Err(e)));
+        }
+        let on_error = self.on_error.as_mut().unwrap();
+        if let Err(e) = on_error(self.buf, &e) {
+            #[cfg(feature = "log")]
+            error!("failed to write error: {:?}", e);
+            return Poll::Ready(Some(Err(e)));
+        }
+        let bytes = self.bytes();
+        self.send_http_trailers(StreamStatus::Error);
+        Poll::Ready(Some(Ok(bytes)))
+    }
+    // retain the last item for the trailer or the cursor.
+    #[inline]
+    fn keep_item(&mut self, record: <InnerStream as TryStream>::Ok) {
+        if self.trailer.is_some() || self.cursor.is_some() {
+            match self.last_item.as_mut() {
+                Some(last_item) => **last_item = record,
+                None => *self.last_item = Some(Box::new(record)),
+            }
+        }
+    }
+    // allocate the buffer, from the pool if any, before writing to it.
+    #[inline]
+    fn reserve_buf(&mut self) {
+        if self.buf.0.capacity() == 0 {
+            let size = (*self.buf_size).max(*self.item_size);
+            match self.pool.as_ref() {
+                Some(pool) => *self.buf = BytesWriter(pool.get(size)),
+                None => self.buf.0.reserve(size),
+            }
+        }
     }
     // use the serializer to write one item to the buffer.
     #[inline]
     fn write_item(&mut self, record: &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> {
-        (self.serializer)(&mut self.buf, record)
+        (self.serializer)(self.buf, record)
     }
 }
 
+// the statistics, including the buffered output, at the trailer.
+fn final_stats(stats: &StreamStats, checksum: Option<&Sha256>, buf: &BytesWriter) -> StreamStats {
+    let mut stats = stats.clone();
+    stats.byte_count += buf.0.len();
+    if let Some(checksum) = checksum {
+        let mut checksum = checksum.clone();
+        checksum.update(&buf.0);
+        stats.sha256 = Some(to_hex(&checksum.finalize()));
+    }
+    stats
+}
+
 impl<InnerStream, InnerError, Serializer, OuterError> Stream
     for ByteStream<InnerStream, InnerError, Serializer, OuterError>
 where
@@ -155,68 +499,150 @@ where
     type Item = Result<Bytes, OuterError>;
 
     #[inline]
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         use Poll::*;
         use State::*;
-        match self.state {
+        let mut this = self.project();
+        if !matches!(*this.state, Done) {
+            this.reserve_buf();
+        }
+        match *this.state {
             Unused => {
-                self.state = Empty;
-                self.put_prefix();
+                this.stats.started = Instant::now();
+                match this.resumed.take() {
+                    // the prefix and preamble were sent before the checkpoint.
+                    Some(state) => {
+                        *this.state = state;
+                        *this.preamble = None;
+                    }
+                    None => {
+                        *this.state = Empty;
+                        if this.preamble.is_none() {
+                            this.put_prefix();
+                        }
+                    }
+                }
             }
             Done => return Ready(None),
             _ => (),
         }
-        loop {
-            match self.inner_stream.as_mut().try_poll_next(cx) {
-                Ready(Some(Ok(record))) => {
-                    #[cfg(feature = "log")]
-                    {
-                        self.item_count += 1;
+        let expired = match this.timeouts.as_mut() {
+            Some(timeouts) => timeouts.check(cx, this.stats),
+            None => None,
+        };
+        // the inner stream is dropped after a timeout.
+        let poll = if let Some(e) = expired {
+            *this.state = Done;
+            this.inner_stream.set(None);
+            Ready(Some(Err(e)))
+        } else {
+            loop {
+                let inner_stream = match this.inner_stream.as_mut().as_pin_mut() {
+                    Some(inner_stream) => inner_stream,
+                    None => break Ready(None),
+                };
+                match inner_stream.try_poll_next(cx) {
+                    Ready(Some(Ok(record))) => {
+                        this.stats.item_count += 1;
+                        if let Some(timeouts) = this.timeouts.as_mut() {
+                            timeouts.item();
+                        }
+                        // where the item starts, including its delimiter.
+                        let mut item_start = this.buf.0.len();
+                        match *this.state {
+                            Empty => {
+                                *this.state = NonEmpty;
+                                if let Err(e) = this.put_preamble(Some(&record)) {
+                                    #[cfg(feature = "log")]
+                                    error!("failed to write preamble: {:?}", e);
+                                    break Ready(Some(Err(e)));
+                                }
+                                item_start = this.buf.0.len();
+                            }
+                            NonEmpty => this.put_delimiter(),
+                            _ => (),
+                        };
+                        let initial_len = this.buf.0.len();
+                        if let Err(e) = this.write_item(&record) {
+                            #[cfg(feature = "log")]
+                            error!("failed to write: {:?}", e);
+                            // drop the partial item, so that on_error
+                            // writes after a complete one.
+                            this.buf.0.truncate(item_start);
+                            break Ready(Some(Err(e)));
+                        }
+                        this.keep_item(record);
+                        let item_size = this.buf.0.len() - initial_len;
+                        if *this.item_size < item_size {
+                            *this.item_size = item_size.next_power_of_two();
+                        }
+                        let remaining_space = this.buf.0.capacity() - this.buf.0.len();
+                        if item_size <= remaining_space {
+                            continue;
+                        }
+                        break Ready(Some(Ok(this.bytes())));
                     }
-                    match self.state {
-                        Empty => self.state = NonEmpty,
-                        NonEmpty => self.put_delimiter(),
-                        _ => (),
-                    };
-                    let initial_len = self.buf.0.len();
-                    if let Err(e) = self.write_item(&record) {
+                    Ready(Some(Err(e))) => {
                         #[cfg(feature = "log")]
-                        error!("failed to write: {:?}", e);
-                        break Ready(Some(Err(e)));
-                    }
-                    let item_size = self.buf.0.len() - initial_len;
-                    if self.item_size < item_size {
-                        self.item_size = item_size.next_power_of_two();
+                        error!("poll_next: {:?}", e);
+                        break Ready(Some(Err(OuterError::from(e))));
                     }
-                    let remaining_space = self.buf.0.capacity() - self.buf.0.len();
-                    if item_size <= remaining_space {
-                        continue;
+                    Ready(None) => {
+                        *this.state = Done;
+                        if let Err(e) = this.put_preamble(None) {
+                            #[cfg(feature = "log")]
+                            error!("failed to write preamble: {:?}", e);
+                            break Ready(Some(Err(e)));
+                        }
+                        this.put_suffix();
+                        if let Err(e) = this.put_trailer() {
+                            #[cfg(feature = "log")]
+                            error!("failed to write trailer: {:?}", e);
+                            break Ready(Some(Err(e)));
+                        }
+                        break Ready(Some(Ok(this.bytes())));
                     }
-                    break Ready(Some(Ok(self.bytes())));
-                }
-                Ready(Some(Err(e))) => {
-                    #[cfg(feature = "log")]
-                    error!("poll_next: {:?}", e);
-                    break Ready(Some(Err(OuterError::from(e))));
-                }
-                Ready(None) => {
-                    self.state = Done;
-                    self.put_suffix();
-                    break Ready(Some(Ok(self.bytes())));
-                }
-                Pending => {
-                    if self.buf.0.is_empty() {
-                        break Pending;
+                    Pending => {
+                        if this.buf.0.is_empty() {
+                            let non_empty = matches!(*this.state, NonEmpty);
+                            if let Some(e) = this
+                                .timeouts
+                                .as_mut()
+                                .and_then(|timeouts| timeouts.poll_pending(cx, non_empty))
+                            {
+                                *this.state = Done;
+                                this.inner_stream.set(None);
+                                break Ready(Some(Err(e)));
+                            }
+                            break Pending;
+                        }
+                        break Ready(Some(Ok(this.bytes())));
                     }
-                    break Ready(Some(Ok(self.bytes())));
                 }
             }
+        };
+        let poll = match poll {
+            Ready(Some(Err(e))) => this.put_error(e),
+            poll => poll,
+        };
+        if let (Ready(Some(Ok(_))), Some(timeouts)) = (&poll, this.timeouts.as_mut()) {
+            if !matches!(*this.state, Done) {
+                timeouts.chunk_sent(cx);
+            }
         }
+        match &poll {
+            Ready(Some(Err(_))) => this.send_http_trailers(StreamStatus::Error),
+            Ready(Some(Ok(_))) if matches!(*this.state, Done) => {
+                this.send_http_trailers(StreamStatus::Complete)
+            }
+            _ => (),
+        }
+        poll
     }
 }
 
-#[cfg(feature = "log")]
-impl<InnerStream, InnerError, Serializer, OuterError> Drop
+#[pinned_drop]
+impl<InnerStream, InnerError, Serializer, OuterError> PinnedDrop
     for ByteStream<InnerStream, InnerError, Serializer, OuterError>
 where
     InnerError: std::error::Error,
@@ -226,12 +652,15 @@ where
         FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
 {
     #[inline]
-    fn drop(&mut self) {
-        if !matches!(self.state, State::Done) {
+    fn drop(self: Pin<&mut Self>) {
+        let mut this = self.project();
+        #[cfg(feature = "log")]
+        if *this.warn_on_drop && !matches!(*this.state, State::Done) {
             warn!(
                 "dropped ByteStream in state: {:?} after {} items",
-                self.state, self.item_count
+                this.state, this.stats.item_count
             );
         }
+        this.send_http_trailers(StreamStatus::Error);
     }
 }
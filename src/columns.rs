@@ -0,0 +1,189 @@
+use crate::{ByteStream, BytesWriter};
+use futures::TryStream;
+use serde::Serialize;
+use serde_json::Value;
+#[cfg(feature = "sqlx")]
+use sqlx::{database::Database, Column, Describe, Row, TypeInfo};
+
+/// The json type of a column's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonType {
+    Null,
+    Boolean,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    /// The json type of the values of a given database type name.
+    pub fn from_type_name(type_name: &str) -> Self {
+        match type_name.to_ascii_uppercase().as_str() {
+            "NULL" | "VOID" => JsonType::Null,
+            "BOOL" | "BOOLEAN" | "BIT" => JsonType::Boolean,
+            "INT" | "INT2" | "INT4" | "INT8" | "INTEGER" | "SMALLINT" | "BIGINT" | "TINYINT"
+            | "MEDIUMINT" | "FLOAT" | "FLOAT4" | "FLOAT8" | "REAL" | "DOUBLE" | "NUMERIC"
+            | "DECIMAL" | "MONEY" | "OID" | "INT UNSIGNED" | "BIGINT UNSIGNED"
+            | "SMALLINT UNSIGNED" | "TINYINT UNSIGNED" | "MEDIUMINT UNSIGNED" => JsonType::Number,
+            "JSON" | "JSONB" => JsonType::Object,
+            name if name.ends_with("[]") => JsonType::Array,
+            _ => JsonType::String,
+        }
+    }
+    /// The json type of a given json value.
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Boolean,
+            Value::Number(_) => JsonType::Number,
+            Value::String(_) => JsonType::String,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+}
+
+/// The name and type of a column, written in the `cols` preamble.
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// The database type name, if known.
+    #[serde(rename = "type")]
+    pub db_type: Option<String>,
+    /// Whether the column is nullable, if known.
+    pub nullable: Option<bool>,
+    pub json_type: JsonType,
+}
+
+impl ColumnInfo {
+    /// Columns named after the fields of a serde struct or map. The
+    /// json types are taken from the field values, and are null for
+    /// the fields that a struct skips.
+    pub fn from_fields<T: Serialize + ?Sized>(record: &T) -> Result<Vec<Self>, serde_json::Error> {
+        Ok(crate::to_fields_with_skipped(record)?
+            .into_iter()
+            .map(|(name, value)| ColumnInfo {
+                name,
+                db_type: None,
+                nullable: None,
+                json_type: JsonType::of(&value),
+            })
+            .collect())
+    }
+    /// Columns of a row. Nullability is not known from a row.
+    #[cfg(feature = "sqlx")]
+    pub fn from_row<R: Row>(row: &R) -> Vec<Self> {
+        row.columns()
+            .iter()
+            .map(|col| ColumnInfo {
+                name: col.name().to_string(),
+                db_type: Some(col.type_info().name().to_string()),
+                nullable: None,
+                json_type: JsonType::from_type_name(col.type_info().name()),
+            })
+            .collect()
+    }
+    /// Columns of a described statement, e.g. from `pool.describe(sql)`.
+    #[cfg(feature = "sqlx")]
+    pub fn from_describe<DB: Database>(describe: &Describe<DB>) -> Vec<Self> {
+        describe
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(i, col)| ColumnInfo {
+                name: col.name().to_string(),
+                db_type: Some(col.type_info().name().to_string()),
+                nullable: describe.nullable(i),
+                json_type: JsonType::from_type_name(col.type_info().name()),
+            })
+            .collect()
+    }
+}
+
+// the column name, so that columns can be given to
+// to_writer_positional.
+impl AsRef<str> for ColumnInfo {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The prefix of a columnar document having the given columns:
+/// `{"cols":[...],"rows":[`.
+pub fn columns_prefix(columns: &[ColumnInfo]) -> Result<String, serde_json::Error> {
+    Ok(format!(
+        r#"{{"cols":{},"rows":["#,
+        serde_json::to_string(columns)?
+    ))
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + From<serde_json::Error> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Write a columnar document, `{"cols":[...],"rows":[...]}`,
+    /// where the columns are given the first item, or None if the
+    /// stream is empty. The serializer should write each item as an
+    /// array, e.g. using `to_writer_positional`.
+    pub fn columns<F>(self, mut columns: F) -> Self
+    where
+        F: FnMut(Option<&<InnerStream as TryStream>::Ok>) -> Result<Vec<ColumnInfo>, OuterError>
+            + Send
+            + 'static,
+    {
+        self.preamble(move |buf, first| {
+            serde_json::to_writer(&mut *buf, &columns(first)?)?;
+            buf.0.extend_from_slice(br#","rows":["#);
+            Ok(())
+        })
+        .prefix(r#"{"cols":"#)
+        .suffix("]}")
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    <InnerStream as TryStream>::Ok: Serialize,
+    OuterError: From<InnerError> + From<serde_json::Error> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Write a columnar document whose columns are named after the
+    /// fields of the first record. The serializer should write each
+    /// record with the same columns, e.g. using `to_writer_positional`
+    /// with the field names.
+    pub fn field_columns(self) -> Self {
+        self.columns(|first| match first {
+            Some(record) => Ok(ColumnInfo::from_fields(record)?),
+            None => Ok(vec![]),
+        })
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    <InnerStream as TryStream>::Ok: Row,
+    OuterError: From<InnerError> + From<serde_json::Error> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Write a columnar document whose columns are taken from the
+    /// first row.
+    pub fn row_columns(self) -> Self {
+        self.columns(|first| Ok(first.map(ColumnInfo::from_row).unwrap_or_default()))
+    }
+}
@@ -0,0 +1,92 @@
+use crate::util::to_hex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{fmt, future::Future};
+
+/// An entity tag, quoted, for the ETag header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag(String);
+
+impl ETag {
+    /// A weak ETag of the result of a cheap version query, e.g.
+    /// `max(updated_at)`, and of the request parameters, so that it
+    /// changes whenever the response would.
+    pub fn weak<V, P>(version: &V, params: &P) -> Result<Self, serde_json::Error>
+    where
+        V: Serialize + ?Sized,
+        P: Serialize + ?Sized,
+    {
+        let mut sha256 = Sha256::new();
+        sha256.update(serde_json::to_vec(version)?);
+        sha256.update([0]);
+        sha256.update(serde_json::to_vec(params)?);
+        // 128 bits are enough to tell versions apart.
+        Ok(ETag(format!("W/\"{}\"", &to_hex(&sha256.finalize())[..32])))
+    }
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    /// True if an If-None-Match header names this ETag.
+    #[inline]
+    pub fn matches(&self, if_none_match: Option<&str>) -> bool {
+        if_none_match_matches(if_none_match, &self.0)
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// the weak comparison of If-None-Match, which ignores the W/ prefix.
+pub(crate) fn if_none_match_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    match if_none_match {
+        Some(header) => header
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag),
+        None => false,
+    }
+}
+
+/// Whether the client already has the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conditional {
+    /// Respond with 304 Not Modified.
+    NotModified(ETag),
+    /// Respond in full, with the ETag header.
+    Modified(ETag),
+}
+
+impl Conditional {
+    #[inline]
+    pub fn etag(&self) -> &ETag {
+        match self {
+            Conditional::NotModified(etag) | Conditional::Modified(etag) => etag,
+        }
+    }
+}
+
+/// Run a version query, and compare the resulting ETag with the
+/// If-None-Match header, before deciding to run the full query.
+pub async fn conditional<V, P, E, Fut>(
+    if_none_match: Option<&str>,
+    params: &P,
+    version: Fut,
+) -> Result<Conditional, E>
+where
+    V: Serialize,
+    P: Serialize + ?Sized,
+    E: From<serde_json::Error>,
+    Fut: Future<Output = Result<V, E>>,
+{
+    let etag = ETag::weak(&version.await?, params)?;
+    Ok(if etag.matches(if_none_match) {
+        Conditional::NotModified(etag)
+    } else {
+        Conditional::Modified(etag)
+    })
+}
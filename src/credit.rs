@@ -0,0 +1,199 @@
+use futures::{
+    task::{Context, Poll, Waker},
+    Stream,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default)]
+struct CreditState {
+    available: usize,
+    cancelled: bool,
+    // the task waiting for credit.
+    waker: Option<Waker>,
+}
+
+/// A handle to grant credit to a CreditStream, or to cancel it, e.g.
+/// on a message from the client.
+#[derive(Debug, Clone, Default)]
+pub struct Credit(Arc<Mutex<CreditState>>);
+
+impl Credit {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Allow the stream to return n more items.
+    pub fn grant(&self, n: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.available = state.available.saturating_add(n);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+    /// End the stream before its next item.
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+    /// The number of items the stream may still return.
+    pub fn available(&self) -> usize {
+        self.0.lock().unwrap().available
+    }
+}
+
+/// A stream that returns only as many items as the client has granted
+/// credit for. Without credit, the inner stream is not polled, so a
+/// query holds its cursor, and rows are not buffered, until the client
+/// asks for more.
+pub struct CreditStream<S> {
+    inner_stream: S,
+    credit: Credit,
+    // removes the stream from its OpenQueries when dropped.
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl<S> CreditStream<S> {
+    /// Start with no credit.
+    pub fn new(inner_stream: S, credit: Credit) -> Self {
+        Self {
+            inner_stream,
+            credit,
+            on_drop: None,
+        }
+    }
+    #[inline]
+    pub fn credit(&self) -> &Credit {
+        &self.credit
+    }
+}
+
+impl<S> Drop for CreditStream<S> {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop();
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for CreditStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        {
+            let mut state = this.credit.0.lock().unwrap();
+            if state.cancelled {
+                return Poll::Ready(None);
+            }
+            if state.available == 0 {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        let poll = Pin::new(&mut this.inner_stream).poll_next(cx);
+        if let Poll::Ready(Some(_)) = poll {
+            this.credit.0.lock().unwrap().available -= 1;
+        }
+        poll
+    }
+}
+
+/// The error when a connection already has its limit of open queries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooManyQueries(pub usize);
+
+impl fmt::Display for TooManyQueries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many open queries, the limit is {}", self.0)
+    }
+}
+
+impl std::error::Error for TooManyQueries {}
+
+/// The queries open on one connection, e.g. a WebSocket, by the id
+/// chosen by the client, so that its credit and cancel messages reach
+/// the right CreditStream. A query is closed when its stream is
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct OpenQueries<K> {
+    queries: Arc<Mutex<HashMap<K, Credit>>>,
+    limit: usize,
+}
+
+impl<K> OpenQueries<K>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    /// Allow at most `limit` concurrent queries.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            queries: Default::default(),
+            limit,
+        }
+    }
+    /// Register a query, failing if the limit is reached. An open query
+    /// having the same id is cancelled and replaced.
+    pub fn open<S>(&self, id: K, inner_stream: S) -> Result<CreditStream<S>, TooManyQueries> {
+        let mut queries = self.queries.lock().unwrap();
+        if let Some(credit) = queries.remove(&id) {
+            credit.cancel();
+        } else if queries.len() >= self.limit {
+            return Err(TooManyQueries(self.limit));
+        }
+        let credit = Credit::new();
+        queries.insert(id.clone(), credit.clone());
+        let mut stream = CreditStream::new(inner_stream, credit.clone());
+        let registry = Arc::downgrade(&self.queries);
+        stream.on_drop = Some(Box::new(move || {
+            if let Some(queries) = registry.upgrade() {
+                let mut queries = queries.lock().unwrap();
+                // unless it was replaced by a query having the same id.
+                if matches!(queries.get(&id), Some(open) if Arc::ptr_eq(&open.0, &credit.0)) {
+                    queries.remove(&id);
+                }
+            }
+        }));
+        Ok(stream)
+    }
+    /// Grant credit to a query. False if it is not open.
+    pub fn grant(&self, id: &K, n: usize) -> bool {
+        match self.queries.lock().unwrap().get(id) {
+            Some(credit) => {
+                credit.grant(n);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Cancel a query. False if it is not open.
+    pub fn cancel(&self, id: &K) -> bool {
+        match self.queries.lock().unwrap().remove(id) {
+            Some(credit) => {
+                credit.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+    /// Cancel every query, e.g. when the connection closes.
+    pub fn cancel_all(&self) {
+        for (_, credit) in self.queries.lock().unwrap().drain() {
+            credit.cancel();
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.queries.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,109 @@
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use sqlx::{
+    postgres::{PgArguments, PgRow},
+    Arguments, Encode, FromRow, PgPool, Postgres, Transaction, Type,
+};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// a unique cursor name, so that cursors never collide, e.g. if a
+// session declares several.
+fn cursor_name() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "sqlx_actix_streaming_cursor_{}",
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A query that is read through a server-side cursor, so that neither
+/// the database nor the driver buffer more than one batch of rows.
+///
+/// The stream owns a transaction that runs `DECLARE ... NO SCROLL
+/// CURSOR FOR` the query, and it runs `FETCH` for the next batch only
+/// once the previous batch has been returned, i.e. when a ByteStream
+/// polls for more. A slow client therefore slows the query instead of
+/// filling buffers. At the end, the cursor is closed and the
+/// transaction committed. If the stream is dropped early, the
+/// transaction is rolled back, which closes the cursor.
+pub struct PgCursor {
+    sql: String,
+    arguments: PgArguments,
+    batch_size: usize,
+}
+
+// the state between batches.
+enum CursorState {
+    Declare(PgPool, String, PgArguments),
+    Fetch(Box<Transaction<'static, Postgres>>),
+    Done,
+}
+
+impl PgCursor {
+    /// The query, which may have parameters: $1, $2...
+    pub fn new(sql: impl Into<String>) -> Self {
+        Self {
+            sql: sql.into(),
+            arguments: PgArguments::default(),
+            batch_size: 1000,
+        }
+    }
+    /// Bind the next parameter of the query.
+    #[inline]
+    pub fn bind<T>(mut self, value: T) -> Self
+    where
+        T: 'static + Send + Encode<'static, Postgres> + Type<Postgres>,
+    {
+        self.arguments.add(value);
+        self
+    }
+    /// The number of rows fetched at a time. 1000 by default.
+    #[inline]
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+    /// Stream the rows of the query, as T.
+    pub fn fetch<T>(self, pool: &PgPool) -> BoxStream<'static, Result<T, sqlx::Error>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'static,
+    {
+        let name = cursor_name();
+        let declare = format!("DECLARE {} NO SCROLL CURSOR FOR {}", name, self.sql);
+        let fetch = format!("FETCH {} FROM {}", self.batch_size, name);
+        let close = format!("CLOSE {}", name);
+        let batch_size = self.batch_size;
+        let state = CursorState::Declare(pool.clone(), declare, self.arguments);
+        stream::try_unfold(state, move |state| {
+            let fetch = fetch.clone();
+            let close = close.clone();
+            async move {
+                let mut tx = match state {
+                    CursorState::Declare(pool, declare, arguments) => {
+                        let mut tx = pool.begin().await?;
+                        sqlx::query_with(&declare, arguments)
+                            .execute(&mut *tx)
+                            .await?;
+                        Box::new(tx)
+                    }
+                    CursorState::Fetch(tx) => tx,
+                    CursorState::Done => return Ok::<_, sqlx::Error>(None),
+                };
+                let rows: Vec<T> = sqlx::query_as(&fetch).fetch_all(&mut *tx).await?;
+                if rows.len() < batch_size {
+                    sqlx::query(&close).execute(&mut *tx).await?;
+                    tx.commit().await?;
+                    Ok(Some((rows, CursorState::Done)))
+                } else {
+                    Ok(Some((rows, CursorState::Fetch(tx))))
+                }
+            }
+        })
+        .map_ok(|rows| stream::iter(rows.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+}
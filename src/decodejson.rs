@@ -0,0 +1,170 @@
+use crate::{Error, JsonType};
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "mssql"))]
+use serde::Serialize;
+use serde_json::value::{to_raw_value, RawValue};
+use sqlx::{Column, Row, TypeInfo};
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "mssql"))]
+use sqlx::{ColumnIndex, Decode, Type};
+
+/// A row whose columns can be decoded as json without knowing their
+/// types at compile time. The rust type is chosen by the database
+/// type name of the column, since the databases check that the rust
+/// type matches, e.g. postgres does not decode an INT4 as an i64.
+pub trait DecodeJson: Row {
+    /// Decode the column at a given index as json text.
+    fn decode_json(&self, index: usize) -> Result<Box<RawValue>, Error>;
+}
+
+// decode a nullable column as a serde value.
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "mssql"))]
+fn json<'r, T, R>(row: &'r R, index: usize) -> Result<Box<RawValue>, Error>
+where
+    R: Row,
+    usize: ColumnIndex<R>,
+    T: Decode<'r, R::Database> + Type<R::Database> + Serialize,
+{
+    Ok(to_raw_value(&row.try_get::<Option<T>, _>(index)?)?)
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::*;
+    use sqlx::{
+        postgres::{types::Oid, PgRow},
+        types::{
+            time::{Date, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset},
+            BigDecimal, JsonValue, Uuid,
+        },
+    };
+
+    // decode a nullable column, and write it as given by f.
+    fn with<'r, T, R, F>(row: &'r R, index: usize, f: F) -> Result<Box<RawValue>, Error>
+    where
+        R: Row,
+        usize: ColumnIndex<R>,
+        T: Decode<'r, R::Database> + Type<R::Database>,
+        F: FnOnce(T) -> Result<Box<RawValue>, Error>,
+    {
+        match row.try_get::<Option<T>, _>(index)? {
+            Some(value) => f(value),
+            None => Ok(to_raw_value(&())?),
+        }
+    }
+
+    // hh:mm:ss, and microseconds if any, which is the precision of
+    // postgres.
+    fn time_text(time: Time) -> String {
+        let mut text = format!(
+            "{:02}:{:02}:{:02}",
+            time.hour(),
+            time.minute(),
+            time.second()
+        );
+        if time.microsecond() != 0 {
+            text.push_str(&format!(".{:06}", time.microsecond()));
+        }
+        text
+    }
+
+    fn string(text: String) -> Result<Box<RawValue>, Error> {
+        Ok(to_raw_value(&text)?)
+    }
+
+    impl DecodeJson for PgRow {
+        /// Booleans, integers, floats, numerics, json and text are
+        /// written as such. Numerics keep their precision. UUIDs are
+        /// written hyphenated, and dates and times in ISO 8601, with
+        /// TIMESTAMPTZ in UTC. Other types are decoded as text, which
+        /// fails for types that postgres does not decode as text.
+        fn decode_json(&self, index: usize) -> Result<Box<RawValue>, Error> {
+            let name = self.columns()[index].type_info().name();
+            match (JsonType::from_type_name(name), name) {
+                (JsonType::Null, _) => Ok(to_raw_value(&())?),
+                (JsonType::Boolean, _) => json::<bool, _>(self, index),
+                (_, "INT2") => json::<i16, _>(self, index),
+                (_, "INT4") => json::<i32, _>(self, index),
+                (_, "INT8") => json::<i64, _>(self, index),
+                (_, "FLOAT4") => json::<f32, _>(self, index),
+                (_, "FLOAT8") => json::<f64, _>(self, index),
+                (_, "OID") => with(self, index, |oid: Oid| Ok(to_raw_value(&oid.0)?)),
+                (_, "NUMERIC") => with(self, index, |n: BigDecimal| {
+                    Ok(RawValue::from_string(n.to_string())?)
+                }),
+                (JsonType::Object, _) => json::<JsonValue, _>(self, index),
+                (_, "UUID") => with(self, index, |uuid: Uuid| string(uuid.to_string())),
+                (_, "DATE") => with(self, index, |date: Date| string(date.to_string())),
+                (_, "TIME") => with(self, index, |time: Time| string(time_text(time))),
+                (_, "TIMESTAMP") => with(self, index, |dt: PrimitiveDateTime| {
+                    string(format!("{}T{}", dt.date(), time_text(dt.time())))
+                }),
+                (_, "TIMESTAMPTZ") => with(self, index, |dt: OffsetDateTime| {
+                    let dt = dt.to_offset(UtcOffset::UTC);
+                    string(format!("{}T{}Z", dt.date(), time_text(dt.time())))
+                }),
+                _ => json::<String, _>(self, index),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use sqlx::{sqlite::SqliteRow, ValueRef};
+
+    impl DecodeJson for SqliteRow {
+        /// Sqlite values have the type of their storage class, rather
+        /// than the declared type of their column, so numbers are
+        /// written as integers or floats as they are stored.
+        fn decode_json(&self, index: usize) -> Result<Box<RawValue>, Error> {
+            let raw = self.try_get_raw(index)?;
+            if raw.is_null() {
+                return Ok(to_raw_value(&())?);
+            }
+            let stored = raw.type_info().name() == "INTEGER";
+            match JsonType::from_type_name(self.columns()[index].type_info().name()) {
+                JsonType::Null => Ok(to_raw_value(&())?),
+                JsonType::Boolean => Ok(to_raw_value(&self.try_get_unchecked::<bool, _>(index)?)?),
+                JsonType::Number if stored => {
+                    Ok(to_raw_value(&self.try_get_unchecked::<i64, _>(index)?)?)
+                }
+                JsonType::Number => Ok(to_raw_value(&self.try_get_unchecked::<f64, _>(index)?)?),
+                _ => Ok(to_raw_value(&self.try_get_unchecked::<String, _>(index)?)?),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl DecodeJson for sqlx::mysql::MySqlRow {
+    fn decode_json(&self, index: usize) -> Result<Box<RawValue>, Error> {
+        decode_by_json_type(self, index)
+    }
+}
+
+#[cfg(feature = "mssql")]
+impl DecodeJson for sqlx::mssql::MssqlRow {
+    fn decode_json(&self, index: usize) -> Result<Box<RawValue>, Error> {
+        decode_by_json_type(self, index)
+    }
+}
+
+// decode a column as a boolean, an integer, a float or text,
+// according to its json type.
+#[cfg(any(feature = "mysql", feature = "mssql"))]
+fn decode_by_json_type<R>(row: &R, index: usize) -> Result<Box<RawValue>, Error>
+where
+    R: Row,
+    usize: ColumnIndex<R>,
+    for<'r> bool: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> i64: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> f64: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> String: Decode<'r, R::Database> + Type<R::Database>,
+{
+    match JsonType::from_type_name(row.columns()[index].type_info().name()) {
+        JsonType::Null => Ok(to_raw_value(&())?),
+        JsonType::Boolean => json::<bool, _>(row, index),
+        JsonType::Number => json::<i64, _>(row, index).or_else(|_| json::<f64, _>(row, index)),
+        _ => json::<String, _>(row, index),
+    }
+}
@@ -0,0 +1,66 @@
+use crate::StreamTimeout;
+use std::fmt;
+
+/// An error from the database, or from writing the output. It can be
+/// used as the OuterError of a ByteStream.
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    Timeout(StreamTimeout),
+    #[cfg(feature = "sqlx")]
+    Sqlx(sqlx::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "json: {}", e),
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::Timeout(e) => write!(f, "timeout: {}", e),
+            #[cfg(feature = "sqlx")]
+            Error::Sqlx(e) => write!(f, "sqlx: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Timeout(e) => Some(e),
+            #[cfg(feature = "sqlx")]
+            Error::Sqlx(e) => Some(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    #[inline]
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    #[inline]
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<StreamTimeout> for Error {
+    #[inline]
+    fn from(e: StreamTimeout) -> Self {
+        Error::Timeout(e)
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl From<sqlx::Error> for Error {
+    #[inline]
+    fn from(e: sqlx::Error) -> Self {
+        Error::Sqlx(e)
+    }
+}
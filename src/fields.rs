@@ -0,0 +1,397 @@
+use serde::ser::{self, Error as _, Impossible, Serialize, SerializeMap, SerializeStruct};
+use serde_json::{Error, Value};
+use std::io::Write;
+
+/// Serialize a struct or map into its top-level (name, value) pairs,
+/// in field order.
+pub fn to_fields<T: Serialize + ?Sized>(value: &T) -> Result<Vec<(String, Value)>, Error> {
+    value.serialize(FieldCollector {
+        keep: &|_| true,
+        skipped: false,
+    })
+}
+
+// the fields of a struct or map, including those that a struct skips,
+// e.g. by skip_serializing_if, as nulls.
+pub(crate) fn to_fields_with_skipped<T: Serialize + ?Sized>(
+    value: &T,
+) -> Result<Vec<(String, Value)>, Error> {
+    value.serialize(FieldCollector {
+        keep: &|_| true,
+        skipped: true,
+    })
+}
+
+/// Serialize the fields of a struct or map for which `keep(name)` is
+/// true. The values of other fields are not serialized.
+pub fn to_fields_filtered<T: Serialize + ?Sized>(
+    value: &T,
+    keep: &dyn Fn(&str) -> bool,
+) -> Result<Vec<(String, Value)>, Error> {
+    value.serialize(FieldCollector {
+        keep,
+        skipped: false,
+    })
+}
+
+/// Write a struct or map as a json array of the values of the given
+/// columns, e.g. `[1,"spanner"]` rather than `{"id":1,"name":"spanner"}`.
+/// Fields are matched to the columns by name, so that a skipped field,
+/// e.g. by `skip_serializing_if`, does not shift the later values.
+/// Columns without a field are written as null, and fields that are
+/// not columns are not written. The values are written straight to
+/// the writer while the fields are in column order, as those of a
+/// struct usually are.
+pub fn to_writer_positional<W, T, S>(writer: W, cols: &[S], value: &T) -> Result<(), Error>
+where
+    W: Write,
+    T: Serialize + ?Sized,
+    S: AsRef<str>,
+{
+    value.serialize(Positional { writer, cols })
+}
+
+fn not_a_struct() -> Error {
+    Error::custom("expected a struct or map")
+}
+
+// the string of a map key.
+fn key_string<T: Serialize + ?Sized>(key: &T) -> Result<String, Error> {
+    match serde_json::to_value(key)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(Error::custom("map key must be a string")),
+    }
+}
+
+// a serializer that collects the kept fields of a struct or map, and
+// the skipped fields of a struct as nulls if `skipped`.
+struct FieldCollector<'k> {
+    keep: &'k dyn Fn(&str) -> bool,
+    skipped: bool,
+}
+
+// the fields collected so far, and the pending key of a map entry.
+struct Fields<'k> {
+    keep: &'k dyn Fn(&str) -> bool,
+    skipped: bool,
+    fields: Vec<(String, Value)>,
+    key: Option<String>,
+}
+
+impl<'k> Fields<'k> {
+    fn with_capacity(collector: FieldCollector<'k>, len: usize) -> Self {
+        Self {
+            keep: collector.keep,
+            skipped: collector.skipped,
+            fields: Vec::with_capacity(len),
+            key: None,
+        }
+    }
+}
+
+macro_rules! reject {
+    ( $( $method:ident ( $( $arg:ty ),* ); )* ) => {
+        $(
+            fn $method(self, $( _: $arg ),*) -> Result<Self::Ok, Error> {
+                Err(not_a_struct())
+            }
+        )*
+    };
+}
+
+// reject all but structs and maps, which are serialized as given by
+// $start.
+macro_rules! structs_only {
+    ( $start:ident ) => {
+        reject! {
+            serialize_bool(bool);
+            serialize_i8(i8);
+            serialize_i16(i16);
+            serialize_i32(i32);
+            serialize_i64(i64);
+            serialize_u8(u8);
+            serialize_u16(u16);
+            serialize_u32(u32);
+            serialize_u64(u64);
+            serialize_f32(f32);
+            serialize_f64(f64);
+            serialize_char(char);
+            serialize_str(&str);
+            serialize_bytes(&[u8]);
+            serialize_none();
+            serialize_unit();
+            serialize_unit_struct(&'static str);
+            serialize_unit_variant(&'static str, u32, &'static str);
+        }
+
+        fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+            value.serialize(self)
+        }
+        fn serialize_newtype_struct<T: Serialize + ?Sized>(
+            self,
+            _name: &'static str,
+            value: &T,
+        ) -> Result<Self::Ok, Error> {
+            value.serialize(self)
+        }
+        fn serialize_newtype_variant<T: Serialize + ?Sized>(
+            self,
+            _name: &'static str,
+            _variant_index: u32,
+            _variant: &'static str,
+            _value: &T,
+        ) -> Result<Self::Ok, Error> {
+            Err(not_a_struct())
+        }
+        fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+            Err(not_a_struct())
+        }
+        fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+            Err(not_a_struct())
+        }
+        fn serialize_tuple_struct(
+            self,
+            _name: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeTupleStruct, Error> {
+            Err(not_a_struct())
+        }
+        fn serialize_tuple_variant(
+            self,
+            _name: &'static str,
+            _variant_index: u32,
+            _variant: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeTupleVariant, Error> {
+            Err(not_a_struct())
+        }
+        fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+            $start(self, len.unwrap_or(0))
+        }
+        fn serialize_struct(
+            self,
+            _name: &'static str,
+            len: usize,
+        ) -> Result<Self::SerializeStruct, Error> {
+            $start(self, len)
+        }
+        fn serialize_struct_variant(
+            self,
+            _name: &'static str,
+            _variant_index: u32,
+            _variant: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeStructVariant, Error> {
+            Err(not_a_struct())
+        }
+    };
+}
+
+impl<'k> ser::Serializer for FieldCollector<'k> {
+    type Ok = Vec<(String, Value)>;
+    type Error = Error;
+    type SerializeSeq = Impossible<Self::Ok, Error>;
+    type SerializeTuple = Impossible<Self::Ok, Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = Fields<'k>;
+    type SerializeStruct = Fields<'k>;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    structs_only!(start_fields);
+}
+
+fn start_fields(collector: FieldCollector<'_>, len: usize) -> Result<Fields<'_>, Error> {
+    Ok(Fields::with_capacity(collector, len))
+}
+
+impl<'k> SerializeStruct for Fields<'k> {
+    type Ok = Vec<(String, Value)>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        if (self.keep)(key) {
+            self.fields
+                .push((key.to_string(), serde_json::to_value(value)?));
+        }
+        Ok(())
+    }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        if self.skipped && (self.keep)(key) {
+            self.fields.push((key.to_string(), Value::Null));
+        }
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.fields)
+    }
+}
+
+impl<'k> SerializeMap for Fields<'k> {
+    type Ok = Vec<(String, Value)>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key_string(key)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::custom("map value without a key"))?;
+        if (self.keep)(&key) {
+            self.fields.push((key, serde_json::to_value(value)?));
+        }
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.fields)
+    }
+}
+
+// a serializer that writes the fields of a struct or map as an array
+// of the values of the columns.
+struct Positional<'c, W, S> {
+    writer: W,
+    cols: &'c [S],
+}
+
+// the writer, the index of the next column to write, the values of
+// later columns whose fields came early, and the pending key of a map
+// entry.
+struct PositionalRow<'c, W, S> {
+    writer: W,
+    cols: &'c [S],
+    next: usize,
+    early: Vec<(usize, Vec<u8>)>,
+    key: Option<String>,
+}
+
+impl<'c, W: Write, S: AsRef<str>> ser::Serializer for Positional<'c, W, S> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = PositionalRow<'c, W, S>;
+    type SerializeStruct = PositionalRow<'c, W, S>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    structs_only!(start_row);
+}
+
+fn start_row<W: Write, S>(
+    positional: Positional<'_, W, S>,
+    _len: usize,
+) -> Result<PositionalRow<'_, W, S>, Error> {
+    let Positional { mut writer, cols } = positional;
+    writer.write_all(b"[").map_err(Error::io)?;
+    Ok(PositionalRow {
+        writer,
+        cols,
+        next: 0,
+        early: Vec::new(),
+        key: None,
+    })
+}
+
+impl<'c, W: Write, S: AsRef<str>> PositionalRow<'c, W, S> {
+    // the separator before the value of the next column.
+    fn separator(&mut self) -> Result<(), Error> {
+        if self.next > 0 {
+            self.writer.write_all(b",").map_err(Error::io)?;
+        }
+        Ok(())
+    }
+    // move past the column just written, and write the following
+    // columns whose fields came early.
+    fn advance(&mut self) -> Result<(), Error> {
+        self.next += 1;
+        while let Some(i) = self.early.iter().position(|(col, _)| *col == self.next) {
+            let (_, value) = self.early.swap_remove(i);
+            self.separator()?;
+            self.writer.write_all(&value).map_err(Error::io)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+    fn null(&mut self) -> Result<(), Error> {
+        self.separator()?;
+        self.writer.write_all(b"null").map_err(Error::io)?;
+        self.advance()
+    }
+    fn field<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), Error> {
+        match self.cols[self.next..]
+            .iter()
+            .position(|col| col.as_ref() == name)
+        {
+            Some(0) => {
+                self.separator()?;
+                serde_json::to_writer(&mut self.writer, value)?;
+                self.advance()
+            }
+            Some(i) => {
+                self.early.push((self.next + i, serde_json::to_vec(value)?));
+                Ok(())
+            }
+            // not a column, or one already written.
+            None => Ok(()),
+        }
+    }
+    fn end(mut self) -> Result<(), Error> {
+        while self.next < self.cols.len() {
+            self.null()?;
+        }
+        self.writer.write_all(b"]").map_err(Error::io)
+    }
+}
+
+impl<'c, W: Write, S: AsRef<str>> SerializeStruct for PositionalRow<'c, W, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        match self.cols.get(self.next) {
+            Some(col) if col.as_ref() == key => self.null(),
+            _ => Ok(()),
+        }
+    }
+    fn end(self) -> Result<(), Error> {
+        PositionalRow::end(self)
+    }
+}
+
+impl<'c, W: Write, S: AsRef<str>> SerializeMap for PositionalRow<'c, W, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key_string(key)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::custom("map value without a key"))?;
+        self.field(&key, value)
+    }
+    fn end(self) -> Result<(), Error> {
+        PositionalRow::end(self)
+    }
+}
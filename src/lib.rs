@@ -2,10 +2,61 @@
 #[cfg(feature = "macros")]
 #[macro_use]
 mod macros;
+mod admission;
+mod bufferpool;
 mod bytestream;
+mod columns;
+mod conditional;
+mod credit;
+#[cfg(feature = "postgres")]
+mod cursor;
+#[cfg(feature = "sqlx")]
+mod decodejson;
+mod error;
+mod fields;
+mod live;
+mod merge;
+mod peek;
+mod projection;
+mod redaction;
+mod resume;
+mod sections;
 // mod rowstream;
 mod selfrefstream;
+mod spill;
+mod tee;
+mod throttle;
+mod timeout;
+mod trailers;
+mod util;
+#[cfg(feature = "xlsx")]
+mod xlsx;
 
+pub use admission::*;
+pub use bufferpool::*;
 pub use bytestream::*;
+pub use columns::*;
+pub use conditional::*;
+pub use credit::*;
+#[cfg(feature = "postgres")]
+pub use cursor::*;
+#[cfg(feature = "sqlx")]
+pub use decodejson::*;
+pub use error::*;
+pub use fields::*;
+pub use live::*;
+pub use merge::*;
+pub use peek::*;
+pub use projection::*;
+pub use redaction::*;
+pub use resume::*;
+pub use sections::*;
 // pub use rowstream::*;
 pub use selfrefstream::*;
+pub use spill::*;
+pub use tee::*;
+pub use throttle::*;
+pub use timeout::*;
+pub use trailers::*;
+#[cfg(feature = "xlsx")]
+pub use xlsx::*;
@@ -0,0 +1,194 @@
+use crate::{ByteStream, BytesWriter};
+use futures::{
+    task::{Context, Poll},
+    Stream, TryStream,
+};
+use futures_timer::Delay;
+use serde::Serialize;
+use std::{future::Future, io::Write, pin::Pin, time::Duration};
+
+/// An event of a live stream, written as a server-sent event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiveEvent<T> {
+    /// A row of the initial snapshot.
+    Snapshot(T),
+    /// A row that changed after the stream started.
+    Change(T),
+    /// Sent when the stream has been idle, so that proxies keep the
+    /// connection open.
+    Heartbeat,
+}
+
+impl<T: Serialize> LiveEvent<T> {
+    /// Write the event as `event: change\ndata: {...}\n\n`, or a
+    /// heartbeat as the comment `: heartbeat\n\n`.
+    pub fn write_sse<E>(&self, buf: &mut BytesWriter) -> Result<(), E>
+    where
+        E: From<serde_json::Error> + From<std::io::Error>,
+    {
+        let (event, item) = match self {
+            LiveEvent::Snapshot(item) => ("snapshot", item),
+            LiveEvent::Change(item) => ("change", item),
+            LiveEvent::Heartbeat => {
+                buf.write_all(b": heartbeat\n\n")?;
+                return Ok(());
+            }
+        };
+        write!(buf, "event: {}\ndata: ", event)?;
+        serde_json::to_writer(&mut *buf, item)?;
+        buf.write_all(b"\n\n")?;
+        Ok(())
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Frame the body as server-sent events instead of a JSON array:
+    /// no prefix, delimiter or suffix, since each event is written
+    /// whole by the serializer, e.g. `LiveEvent::write_sse`.
+    #[inline]
+    pub fn sse(self) -> Self {
+        self.prefix("").delimiter("").suffix("")
+    }
+}
+
+/// Inserts a heartbeat in a live stream whenever it has been idle for
+/// the interval. The stream ends when the inner stream ends.
+pub struct HeartbeatStream<S> {
+    inner_stream: S,
+    interval: Duration,
+    // reset on every item.
+    delay: Delay,
+}
+
+impl<S> HeartbeatStream<S> {
+    pub fn new(inner_stream: S, interval: Duration) -> Self {
+        Self {
+            inner_stream,
+            interval,
+            delay: Delay::new(interval),
+        }
+    }
+}
+
+impl<S, T, E> Stream for HeartbeatStream<S>
+where
+    S: Stream<Item = Result<LiveEvent<T>, E>> + Unpin,
+{
+    type Item = Result<LiveEvent<T>, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Poll::Ready(item) = Pin::new(&mut this.inner_stream).poll_next(cx) {
+            this.delay.reset(this.interval);
+            return Poll::Ready(item);
+        }
+        match Pin::new(&mut this.delay).poll(cx) {
+            Poll::Ready(()) => {
+                this.delay.reset(this.interval);
+                Poll::Ready(Some(Ok(LiveEvent::Heartbeat)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod pg {
+    use super::{HeartbeatStream, LiveEvent};
+    use futures::{
+        future::{self, BoxFuture},
+        stream::{self, BoxStream},
+        StreamExt, TryStreamExt,
+    };
+    use sqlx::{
+        postgres::{PgListener, PgNotification},
+        PgPool,
+    };
+    use std::time::Duration;
+
+    /// A live stream of the changes notified on Postgres channels,
+    /// optionally preceded by a snapshot of the current rows.
+    ///
+    /// The channels are subscribed to before the snapshot is read, so
+    /// that no change is missed between the two. The stream owns its
+    /// PgListener: when it is dropped, e.g. because the client went
+    /// away, the listener runs `UNLISTEN *` and its connection returns
+    /// to the pool.
+    pub struct PgLive<T> {
+        channels: Vec<String>,
+        snapshot: Option<BoxStream<'static, Result<T, sqlx::Error>>>,
+        heartbeat: Option<Duration>,
+    }
+
+    impl<T: Send + 'static> PgLive<T> {
+        pub fn new<I, S>(channels: I) -> Self
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>,
+        {
+            Self {
+                channels: channels.into_iter().map(Into::into).collect(),
+                snapshot: None,
+                heartbeat: None,
+            }
+        }
+        /// The rows to send first, e.g. a SelfRefStream of a query.
+        #[inline]
+        pub fn snapshot(
+            mut self,
+            snapshot: impl futures::Stream<Item = Result<T, sqlx::Error>> + Send + 'static,
+        ) -> Self {
+            self.snapshot = Some(snapshot.boxed());
+            self
+        }
+        /// Send a heartbeat when no event was sent for the interval.
+        #[inline]
+        pub fn heartbeat(mut self, interval: Duration) -> Self {
+            self.heartbeat = Some(interval);
+            self
+        }
+        /// Subscribe to the channels and return the live stream.
+        /// `on_notify` maps each notification to a change, by parsing
+        /// its payload or by fetching the row it names, or skips it
+        /// with `None`.
+        pub async fn listen<F>(
+            self,
+            pool: &PgPool,
+            on_notify: F,
+        ) -> Result<BoxStream<'static, Result<LiveEvent<T>, sqlx::Error>>, sqlx::Error>
+        where
+            F: FnMut(PgNotification) -> BoxFuture<'static, Result<Option<T>, sqlx::Error>>
+                + Send
+                + 'static,
+        {
+            let mut listener = PgListener::connect_with(pool).await?;
+            listener
+                .listen_all(self.channels.iter().map(String::as_str))
+                .await?;
+            let changes = listener
+                .into_stream()
+                .and_then(on_notify)
+                .try_filter_map(|change| future::ok(change.map(LiveEvent::Change)));
+            let snapshot = match self.snapshot {
+                Some(snapshot) => snapshot.map_ok(LiveEvent::Snapshot).boxed(),
+                None => stream::empty().boxed(),
+            };
+            let events = snapshot.chain(changes).boxed();
+            Ok(match self.heartbeat {
+                Some(interval) => HeartbeatStream::new(events, interval).boxed(),
+                None => events,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub use pg::*;
@@ -16,8 +16,8 @@ macro_rules! json_response [
                             { $query }.fetch(pool)
                         }
                     ),
-                    |buf: &mut BytesWriter, rec| {
-                        serde_json::to_writer(buf, rec)
+                    |buf: &mut $crate::BytesWriter, rec| {
+                        serde_json::to_writer(buf, rec).map_err($crate::Error::from)
                     },
                 )
             )
@@ -41,8 +41,8 @@ macro_rules! query_json [
                                 .fetch(pool)
                         }
                     ),
-                    |buf: &mut BytesWriter, rec| {
-                        serde_json::to_writer(buf, rec)
+                    |buf: &mut $crate::BytesWriter, rec| {
+                        serde_json::to_writer(buf, rec).map_err($crate::Error::from)
                     },
                 )
             )
@@ -62,8 +62,8 @@ macro_rules! byte_stream [
                     { $query }.fetch(pool)
                 }
             ),
-            |buf: &mut BytesWriter, rec| {
-                serde_json::to_writer(buf, rec)
+            |buf: &mut $crate::BytesWriter, rec| {
+                serde_json::to_writer(buf, rec).map_err($crate::Error::from)
             },
         )
     });
@@ -94,8 +94,8 @@ macro_rules! json_response_alt [
                                 .fetch(pool)
                         }
                     ),
-                    |buf: &mut BytesWriter, rec: & $item_struct| {
-                        serde_json::to_writer(buf, rec)
+                    |buf: &mut $crate::BytesWriter, rec: & $item_struct| {
+                        serde_json::to_writer(buf, rec).map_err($crate::Error::from)
                     },
                 )
             )
@@ -121,8 +121,8 @@ macro_rules! json_response_alt [
                                 .fetch(pool)
                         }
                     ),
-                    |buf: &mut BytesWriter, rec: & $item_struct| {
-                        serde_json::to_writer(buf, rec)
+                    |buf: &mut $crate::BytesWriter, rec: & $item_struct| {
+                        serde_json::to_writer(buf, rec).map_err($crate::Error::from)
                     },
                 )
             )
@@ -144,8 +144,8 @@ macro_rules! json_response_alt [
                                 .fetch(pool)
                         }
                     ),
-                    |buf: &mut BytesWriter, rec: & $item_struct| {
-                        serde_json::to_writer(buf, rec)
+                    |buf: &mut $crate::BytesWriter, rec: & $item_struct| {
+                        serde_json::to_writer(buf, rec).map_err($crate::Error::from)
                     },
                 )
             )
@@ -171,8 +171,8 @@ macro_rules! json_stream [
                         .fetch(pool)
                 }
             ),
-            |buf: &mut BytesWriter, row| {
-                serde_json::to_writer(buf, row)
+            |buf: &mut $crate::BytesWriter, row: & $item_struct| {
+                serde_json::to_writer(buf, row).map_err($crate::Error::from)
             },
         )
     });
@@ -190,8 +190,8 @@ macro_rules! json_stream [
                         .fetch(pool)
                 }
             ),
-            |buf: &mut BytesWriter, row| {
-                serde_json::to_writer(buf, row)
+            |buf: &mut $crate::BytesWriter, row: & $item_struct| {
+                serde_json::to_writer(buf, row).map_err($crate::Error::from)
             },
         )
     });
@@ -203,21 +203,20 @@ macro_rules! query_stream [
       $sql:literal,
       $( $arg:expr ),*
     ) => ({
-        $crate::SelfRefStream::make(
+        $crate::SelfRefStream::build(
             $pool,
             move |pool| {
-                sqlx::query(sql)
+                sqlx::query($sql)
                     $( .bind($arg) )*
                     .fetch(pool)
             }
         )
     });
-    ( $item_struct:path,
-      $pool:expr,
+    ( $pool:expr,
       $sql:expr,
       $( $arg:expr ),*
     ) => ({
-        $crate::SelfRefStream::make(
+        $crate::SelfRefStream::build(
             ($pool, $sql),
             move |(pool, sql)| {
                 sqlx::query(sql)
@@ -235,7 +234,7 @@ macro_rules! query_as_stream [
       $sql:literal,
       $( $arg:literal ),*
     ) => ({
-        $crate::SelfRefStream::make(
+        $crate::SelfRefStream::build(
             ($pool, $sql.to_string()),
             |(pool, _sql)| {
                 sqlx::query_as!(
@@ -252,7 +251,7 @@ macro_rules! query_as_stream [
       $sql:expr,
       $( $arg:expr ),*
     ) => ({
-        $crate::SelfRefStream::make(
+        $crate::SelfRefStream::build(
             ($pool, $sql),
             move |(pool, sql)| {
                 sqlx::query_as::<_, $item_struct>(sql)
@@ -271,8 +270,8 @@ macro_rules! query_as_byte_stream [
       $fn:expr,
       $( $arg:literal ),*
     ) => ({
-        $crate::byte_stream(
-            $crate::SelfRefStream::make(
+        $crate::ByteStream::new(
+            $crate::SelfRefStream::build(
                 $pool,
                 move |pool| {
                     sqlx::query_as!(
@@ -292,8 +291,8 @@ macro_rules! query_as_byte_stream [
       $fn:expr,
       $( $arg:expr ),*
     ) => ({
-        $crate::sql_byte_stream(
-            $crate::SelfRefStream::make(
+        $crate::ByteStream::new(
+            $crate::SelfRefStream::build(
                 ($pool, $sql),
                 move |(pool, sql)| {
                     sqlx::query_as::<_, $item_struct>(sql)
@@ -313,8 +312,8 @@ macro_rules! query_byte_stream [
       $fn:expr,
       $( $arg:literal ),*
     ) => ({
-        $crate::byte_stream(
-            $crate::SelfRefStream::make(
+        $crate::ByteStream::new(
+            $crate::SelfRefStream::build(
                 $pool,
                 move |pool| {
                     sqlx::query!(
@@ -332,8 +331,8 @@ macro_rules! query_byte_stream [
       $fn:expr,
       $( $arg:expr ),*
     ) => ({
-        $crate::sql_byte_stream(
-            $crate::SelfRefStream::make(
+        $crate::ByteStream::new(
+            $crate::SelfRefStream::build(
                 ($pool, $sql),
                 move |(pool, sql)| {
                     sqlx::query::<_>(sql)
@@ -0,0 +1,211 @@
+use futures::{
+    task::{Context, Poll},
+    Stream, TryStream, TryStreamExt,
+};
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// An error from one shard of a MergeStream.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ShardError {
+    /// The index of the shard in the list given to the MergeStream.
+    pub shard: usize,
+    pub error: String,
+}
+
+/// A handle to the errors reported by the shards of a MergeStream,
+/// e.g. to write them in the summary of an envelope.
+#[derive(Debug, Clone, Default)]
+pub struct ShardErrors(Arc<Mutex<Vec<ShardError>>>);
+
+impl ShardErrors {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+    pub fn to_vec(&self) -> Vec<ShardError> {
+        self.0.lock().unwrap().clone()
+    }
+    fn push(&self, error: ShardError) {
+        self.0.lock().unwrap().push(error);
+    }
+}
+
+/// What a MergeStream does when a shard fails.
+#[derive(Debug, Clone)]
+pub enum ShardErrorPolicy {
+    /// Return the error, and end the merged stream.
+    FailFast,
+    /// Record the error, drop the shard, and continue with the others.
+    Report(ShardErrors),
+}
+
+/// Merges the streams of several shards, which are polled
+/// concurrently. An ordered merge requires each shard to be ordered by
+/// the key, and returns the item having the least key among the
+/// shards. An unordered merge returns items as they arrive.
+pub struct MergeStream<S: TryStream, K, F> {
+    // None once a shard is done.
+    shards: Vec<Option<S>>,
+    // the next item from each shard, and its key, in an ordered merge.
+    heads: Vec<Option<(K, S::Ok)>>,
+    key: Option<F>,
+    // the shard to poll first in an unordered merge.
+    next: usize,
+    policy: ShardErrorPolicy,
+}
+
+impl<S, K, F> MergeStream<S, K, F>
+where
+    S: TryStream + Unpin,
+    S::Error: Display,
+    K: Ord,
+    F: FnMut(&S::Ok) -> K,
+{
+    /// Merge shards that are each ordered by the key.
+    pub fn ordered(shards: Vec<S>, key: F) -> Self {
+        Self {
+            heads: shards.iter().map(|_| None).collect(),
+            shards: shards.into_iter().map(Some).collect(),
+            key: Some(key),
+            next: 0,
+            policy: ShardErrorPolicy::FailFast,
+        }
+    }
+}
+
+impl<S> MergeStream<S, (), fn(&S::Ok)>
+where
+    S: TryStream + Unpin,
+    S::Error: Display,
+{
+    /// Merge shards in the order that their items arrive.
+    pub fn unordered(shards: Vec<S>) -> Self {
+        Self {
+            heads: Vec::new(),
+            shards: shards.into_iter().map(Some).collect(),
+            key: None,
+            next: 0,
+            policy: ShardErrorPolicy::FailFast,
+        }
+    }
+}
+
+impl<S, K, F> MergeStream<S, K, F>
+where
+    S: TryStream + Unpin,
+    S::Error: Display,
+{
+    /// Set what to do when a shard fails. FailFast by default.
+    #[inline]
+    pub fn policy(mut self, policy: ShardErrorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    // poll one shard, returning an error only if it fails the merge.
+    fn poll_shard(
+        &mut self,
+        i: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<S::Ok, S::Error>>> {
+        let shard = match self.shards[i].as_mut() {
+            Some(shard) => shard,
+            None => return Poll::Ready(None),
+        };
+        match shard.try_poll_next_unpin(cx) {
+            Poll::Ready(Some(Err(e))) => match &self.policy {
+                ShardErrorPolicy::FailFast => {
+                    self.shards.iter_mut().for_each(|shard| *shard = None);
+                    self.heads.iter_mut().for_each(|head| *head = None);
+                    Poll::Ready(Some(Err(e)))
+                }
+                ShardErrorPolicy::Report(errors) => {
+                    errors.push(ShardError {
+                        shard: i,
+                        error: e.to_string(),
+                    });
+                    self.shards[i] = None;
+                    Poll::Ready(None)
+                }
+            },
+            Poll::Ready(None) => {
+                self.shards[i] = None;
+                Poll::Ready(None)
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S, K, F> Stream for MergeStream<S, K, F>
+where
+    S: TryStream + Unpin,
+    S::Error: Display,
+    S::Ok: Unpin,
+    K: Ord + Unpin,
+    F: FnMut(&S::Ok) -> K + Unpin,
+{
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut pending = false;
+        if this.key.is_none() {
+            let n = this.shards.len();
+            for j in 0..n {
+                let i = (this.next + j) % n;
+                match this.poll_shard(i, cx) {
+                    Poll::Ready(Some(item)) => {
+                        this.next = i + 1;
+                        return Poll::Ready(Some(item));
+                    }
+                    Poll::Ready(None) => (),
+                    Poll::Pending => pending = true,
+                }
+            }
+            return if pending {
+                Poll::Pending
+            } else {
+                Poll::Ready(None)
+            };
+        }
+        // fill the head of each shard that is not done.
+        for i in 0..this.shards.len() {
+            if this.heads[i].is_some() {
+                continue;
+            }
+            match this.poll_shard(i, cx) {
+                Poll::Ready(Some(Ok(item))) => {
+                    let key = (this.key.as_mut().unwrap())(&item);
+                    this.heads[i] = Some((key, item));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => (),
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            return Poll::Pending;
+        }
+        // the least key, or the first shard among equal keys.
+        let least = this
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|(key, _)| (key, i)))
+            .min()
+            .map(|(_, i)| i);
+        Poll::Ready(
+            least
+                .and_then(|i| this.heads[i].take())
+                .map(|(_, item)| Ok(item)),
+        )
+    }
+}
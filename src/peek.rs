@@ -0,0 +1,70 @@
+use futures::{
+    task::{Context, Poll},
+    Stream, TryStream, TryStreamExt,
+};
+use std::pin::Pin;
+
+/// A stream whose first item has already been polled, so that the
+/// response status can be chosen before any of the body is sent: an
+/// error in the query, or an empty result, can still be returned as
+/// an error response instead of `200 OK` with a broken body.
+pub struct PeekedStream<S: TryStream> {
+    first: Option<S::Ok>,
+    inner_stream: S,
+    done: bool,
+}
+
+impl<S> PeekedStream<S>
+where
+    S: TryStream + Unpin,
+{
+    /// Poll the inner stream up to its first item, its end, or an
+    /// error, which is returned.
+    pub async fn new(mut inner_stream: S) -> Result<Self, S::Error> {
+        let first = inner_stream.try_next().await?;
+        Ok(Self {
+            done: first.is_none(),
+            first,
+            inner_stream,
+        })
+    }
+    /// True if the inner stream ended without any items.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.done
+    }
+    /// The first item, which will be replayed by the stream.
+    #[inline]
+    pub fn first(&self) -> Option<&S::Ok> {
+        self.first.as_ref()
+    }
+    /// Fail with the given error if the inner stream is empty, e.g.
+    /// to return 404 Not Found.
+    #[inline]
+    pub fn non_empty<E>(self, err: impl FnOnce() -> E) -> Result<Self, E> {
+        if self.is_empty() {
+            Err(err())
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+impl<S> Stream for PeekedStream<S>
+where
+    S: TryStream + Unpin,
+    S::Ok: Unpin,
+{
+    type Item = Result<S::Ok, S::Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(first) = self.first.take() {
+            return Poll::Ready(Some(Ok(first)));
+        }
+        if self.done {
+            return Poll::Ready(None);
+        }
+        self.inner_stream.try_poll_next_unpin(cx)
+    }
+}
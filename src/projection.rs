@@ -0,0 +1,152 @@
+use crate::to_fields_filtered;
+#[cfg(feature = "sqlx")]
+use crate::{DecodeJson, Error};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::Value;
+#[cfg(feature = "sqlx")]
+use sqlx::Column;
+use std::{fmt, io::Write, sync::Arc};
+
+/// The fields that clients may request, and the names under which
+/// they are written.
+#[derive(Debug, Clone, Default)]
+pub struct FieldAllowlist {
+    // (source name, output name)
+    fields: Vec<(String, String)>,
+}
+
+impl FieldAllowlist {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Allow a field, written under its own name.
+    #[inline]
+    pub fn field<S: ToString>(self, name: S) -> Self {
+        let name = name.to_string();
+        self.rename(name.clone(), name)
+    }
+    /// Allow a field, written and requested under another name.
+    #[inline]
+    pub fn rename<S: ToString, T: ToString>(mut self, source: S, output: T) -> Self {
+        self.fields.push((source.to_string(), output.to_string()));
+        self
+    }
+    /// Parse a comma separated list of requested field names, such as
+    /// the `fields` query parameter, into a projection. If there is no
+    /// list, then all allowed fields are written, in allowlist order.
+    /// Unknown or repeated names are rejected, so that the request can
+    /// fail before the query starts.
+    pub fn project(&self, requested: Option<&str>) -> Result<Projection, ProjectionError> {
+        let requested = match requested.map(str::trim) {
+            None | Some("") => return Ok(Projection(Arc::new(self.fields.clone()))),
+            Some(requested) => requested,
+        };
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for name in requested
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let field = self
+                .fields
+                .iter()
+                .find(|(_, output)| output == name)
+                .ok_or_else(|| ProjectionError::UnknownField(name.to_string()))?;
+            if fields.iter().any(|(_, output)| output == name) {
+                return Err(ProjectionError::DuplicateField(name.to_string()));
+            }
+            fields.push(field.clone());
+        }
+        Ok(Projection(Arc::new(fields)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectionError {
+    UnknownField(String),
+    DuplicateField(String),
+}
+
+impl fmt::Display for ProjectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectionError::UnknownField(name) => write!(f, "unknown field: {}", name),
+            ProjectionError::DuplicateField(name) => write!(f, "duplicate field: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for ProjectionError {}
+
+/// The requested fields, in the requested order. Cloning is cheap.
+#[derive(Debug, Clone)]
+pub struct Projection(Arc<Vec<(String, String)>>);
+
+impl Projection {
+    /// The output names of the projected fields.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(_, output)| output.as_str())
+    }
+    /// Wrap a struct or map so that it serializes only the projected
+    /// fields. Missing fields are written as null.
+    #[inline]
+    pub fn apply<'a, T: Serialize + ?Sized>(&'a self, record: &'a T) -> Projected<'a, T> {
+        Projected {
+            projection: self,
+            record,
+        }
+    }
+    /// Write the projected fields of a record as a json object.
+    #[inline]
+    pub fn to_writer<W: Write, T: Serialize + ?Sized>(
+        &self,
+        writer: W,
+        record: &T,
+    ) -> Result<(), serde_json::Error> {
+        serde_json::to_writer(writer, &self.apply(record))
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl Projection {
+    /// Write the projected columns of an untyped row as a json object.
+    /// Only the projected columns are decoded, by their database type,
+    /// see DecodeJson. Missing columns are written as null.
+    pub fn row_to_writer<W: Write, R: DecodeJson>(&self, writer: W, row: &R) -> Result<(), Error> {
+        let mut serializer = serde_json::Serializer::new(writer);
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (source, output) in self.0.iter() {
+            match row.columns().iter().position(|col| col.name() == source) {
+                Some(index) => map.serialize_entry(output, &row.decode_json(index)?)?,
+                None => map.serialize_entry(output, &Value::Null)?,
+            }
+        }
+        map.end()?;
+        Ok(())
+    }
+}
+
+/// A record that serializes only the fields of a projection.
+pub struct Projected<'a, T: ?Sized> {
+    projection: &'a Projection,
+    record: &'a T,
+}
+
+impl<'a, T: Serialize + ?Sized> Serialize for Projected<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = &self.projection.0;
+        let values = to_fields_filtered(self.record, &|name| {
+            fields.iter().any(|(source, _)| source == name)
+        })
+        .map_err(serde::ser::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for (source, output) in fields.iter() {
+            match values.iter().find(|(name, _)| name == source) {
+                Some((_, value)) => map.serialize_entry(output, value)?,
+                None => map.serialize_entry(output, &Value::Null)?,
+            }
+        }
+        map.end()
+    }
+}
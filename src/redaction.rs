@@ -0,0 +1,196 @@
+use crate::{to_fields, util::to_hex};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use sha2::Sha256;
+use std::{collections::HashMap, io::Write, sync::Arc};
+
+/// What to do with the value of a redacted field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redaction {
+    /// Remove the field.
+    Drop,
+    /// Replace the value with null.
+    Null,
+    /// Replace the value with the hex HMAC-SHA256 of its text, using
+    /// the policy's key, so that equal values can still be correlated.
+    /// Without a key, the value is replaced with null.
+    Hash,
+    /// Replace all but the last N characters with '*'.
+    Mask(usize),
+    /// Keep only the first N characters.
+    Truncate(usize),
+}
+
+/// Redactions by field name. A name containing a '.', such as
+/// `contact.phone`, matches the field at that path. Other names
+/// match fields of that name at any depth, including in arrays.
+#[derive(Clone, Default)]
+pub struct RedactionPolicy {
+    rules: Vec<(String, Redaction)>,
+    key: Option<Arc<[u8]>>,
+}
+
+impl RedactionPolicy {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the key for Redaction::Hash.
+    #[inline]
+    pub fn hmac_key<K: AsRef<[u8]>>(mut self, key: K) -> Self {
+        self.key = Some(key.as_ref().into());
+        self
+    }
+    /// Redact the given field.
+    #[inline]
+    pub fn rule<S: ToString>(mut self, field: S, redaction: Redaction) -> Self {
+        self.rules.push((field.to_string(), redaction));
+        self
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// Redact a json value in place.
+    pub fn redact(&self, value: &mut Value) {
+        if !self.is_empty() {
+            self.redact_at(&mut String::new(), value);
+        }
+    }
+    /// Serialize a record to a json value, and redact it. Note that the
+    /// fields of a json object are sorted by name.
+    pub fn apply<T: Serialize + ?Sized>(&self, record: &T) -> Result<Value, serde_json::Error> {
+        let mut value = serde_json::to_value(record)?;
+        self.redact(&mut value);
+        Ok(value)
+    }
+    /// Write a struct or map as json, after it has been redacted. The
+    /// order of its fields is preserved.
+    pub fn to_writer<W: Write, T: Serialize + ?Sized>(
+        &self,
+        writer: W,
+        record: &T,
+    ) -> Result<(), serde_json::Error> {
+        if self.is_empty() {
+            return serde_json::to_writer(writer, record);
+        }
+        let mut fields = to_fields(record)?;
+        let mut path = String::new();
+        fields.retain_mut(|(name, value)| self.redact_field(&mut path, name, value));
+        Serializer::collect_map(
+            &mut serde_json::Serializer::new(writer),
+            fields.iter().map(|(name, value)| (name, value)),
+        )
+    }
+    // the redaction of the field having the given name and path.
+    fn rule_for(&self, name: &str, path: &str) -> Option<&Redaction> {
+        self.rules
+            .iter()
+            .find(|(field, _)| {
+                if field.contains('.') {
+                    field == path
+                } else {
+                    field == name
+                }
+            })
+            .map(|(_, redaction)| redaction)
+    }
+    // redact the fields of a value whose path is given.
+    fn redact_at(&self, path: &mut String, value: &mut Value) {
+        match value {
+            Value::Object(map) => map.retain(|name, value| self.redact_field(path, name, value)),
+            Value::Array(values) => {
+                for value in values {
+                    self.redact_at(path, value);
+                }
+            }
+            _ => (),
+        }
+    }
+    // redact a field of the object at the given path. Return whether to
+    // keep it.
+    fn redact_field(&self, path: &mut String, name: &str, value: &mut Value) -> bool {
+        let len = path.len();
+        if len > 0 {
+            path.push('.');
+        }
+        path.push_str(name);
+        let keep = match self.rule_for(name, path) {
+            Some(Redaction::Drop) => false,
+            Some(redaction) => {
+                *value = self.redacted(redaction, value);
+                true
+            }
+            None => {
+                self.redact_at(path, value);
+                true
+            }
+        };
+        path.truncate(len);
+        keep
+    }
+    // the redacted replacement for a value.
+    fn redacted(&self, redaction: &Redaction, value: &Value) -> Value {
+        if value.is_null() {
+            return Value::Null;
+        }
+        let text = match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
+        match redaction {
+            Redaction::Drop | Redaction::Null => Value::Null,
+            Redaction::Hash => match &self.key {
+                Some(key) => {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+                        .expect("HMAC can take a key of any size");
+                    mac.update(text.as_bytes());
+                    Value::String(to_hex(&mac.finalize().into_bytes()))
+                }
+                None => Value::Null,
+            },
+            Redaction::Mask(keep_last) => {
+                let len = text.chars().count();
+                let masked = len.saturating_sub(*keep_last);
+                Value::String(
+                    text.chars()
+                        .enumerate()
+                        .map(|(i, c)| if i < masked { '*' } else { c })
+                        .collect(),
+                )
+            }
+            Redaction::Truncate(len) => Value::String(text.chars().take(*len).collect()),
+        }
+    }
+}
+
+/// Redaction policies by role, e.g. taken from the caller's
+/// credentials. Unknown roles get the default policy, which should
+/// be the most restrictive.
+#[derive(Clone, Default)]
+pub struct RedactionPolicies {
+    default: RedactionPolicy,
+    roles: HashMap<String, RedactionPolicy>,
+}
+
+impl RedactionPolicies {
+    #[inline]
+    pub fn new(default: RedactionPolicy) -> Self {
+        Self {
+            default,
+            roles: HashMap::new(),
+        }
+    }
+    /// Set the policy of a role.
+    #[inline]
+    pub fn role<S: ToString>(mut self, role: S, policy: RedactionPolicy) -> Self {
+        self.roles.insert(role.to_string(), policy);
+        self
+    }
+    /// The policy of a role, or the default policy.
+    #[inline]
+    pub fn for_role(&self, role: &str) -> &RedactionPolicy {
+        self.roles.get(role).unwrap_or(&self.default)
+    }
+}
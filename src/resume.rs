@@ -0,0 +1,171 @@
+use crate::{ByteStream, BytesWriter, State};
+use futures::TryStream;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// A position in a document at the end of a chunk: its byte offset,
+/// the number of items before it, and the key of the last of them, if
+/// any. Since chunks end between items, an export that is ordered by a
+/// unique key can be resumed from a checkpoint by re-running the query
+/// for the items after the key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub offset: usize,
+    pub item_count: usize,
+    pub key: Option<String>,
+}
+
+/// The resume token of a checkpoint is `offset:item_count:key`, where
+/// the key is a json string, e.g. `1234:56:"56"`, or just `offset:0` if
+/// no item was written. The key is escaped, so the token has no control
+/// characters, and can be sent as a header.
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(
+                f,
+                "{}:{}:{}",
+                self.offset,
+                self.item_count,
+                serde_json::Value::from(key.as_str())
+            ),
+            None => write!(f, "{}:{}", self.offset, self.item_count),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidResumeToken(pub String);
+
+impl fmt::Display for InvalidResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid resume token: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidResumeToken {}
+
+impl FromStr for Checkpoint {
+    type Err = InvalidResumeToken;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidResumeToken(token.to_string());
+        let mut parts = token.splitn(3, ':');
+        let offset = parts.next().unwrap_or_default();
+        let item_count = parts.next().ok_or_else(invalid)?;
+        Ok(Checkpoint {
+            offset: offset.parse().map_err(|_| invalid())?,
+            item_count: item_count.parse().map_err(|_| invalid())?,
+            key: parts
+                .next()
+                .map(serde_json::from_str)
+                .transpose()
+                .map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// The checkpoints of an export, by offset, which are recorded as each
+/// chunk of a ByteStream is emitted. They are kept by the server, e.g.
+/// by export id, so that a client only needs the number of bytes it
+/// received, as in a `Range: bytes=N-` request, to resume. Streams of
+/// the same export may share them, since their output is the same.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoints(Arc<Mutex<BTreeMap<usize, Checkpoint>>>);
+
+impl Checkpoints {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The checkpoint at the end of the last emitted chunk that ends
+    /// between items.
+    pub fn latest(&self) -> Option<Checkpoint> {
+        let checkpoints = self.0.lock().unwrap();
+        checkpoints.values().next_back().cloned()
+    }
+    /// The last checkpoint at or before an offset, from which a client
+    /// having received that many bytes can be resumed.
+    pub fn before(&self, offset: usize) -> Option<Checkpoint> {
+        let checkpoints = self.0.lock().unwrap();
+        checkpoints
+            .range(..=offset)
+            .next_back()
+            .map(|(_, c)| c.clone())
+    }
+    #[inline]
+    pub(crate) fn record(&self, checkpoint: Checkpoint) {
+        self.0.lock().unwrap().insert(checkpoint.offset, checkpoint);
+    }
+}
+
+/// The start of a `Range: bytes=N-` header, which is the number of
+/// bytes a client already has. Other ranges are not supported.
+pub fn range_start(range: &str) -> Option<usize> {
+    range
+        .trim()
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
+
+/// The Content-Range of output resumed at an offset. The length is not
+/// known while streaming, so the range is open.
+pub fn content_range(offset: usize) -> String {
+    format!("bytes {}-*/*", offset)
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Record a checkpoint at the end of each chunk, using the key of
+    /// the last item written. The items must be ordered by the key.
+    pub fn checkpoints<F>(mut self, key: F, checkpoints: &Checkpoints) -> Self
+    where
+        F: FnMut(&<InnerStream as TryStream>::Ok) -> String + Send + 'static,
+    {
+        self.cursor = Some((Box::new(key), checkpoints.clone()));
+        self
+    }
+    /// Continue a document after a checkpoint. The inner stream must
+    /// return the items after the checkpoint's key, e.g. from a query
+    /// having `WHERE key > $1`. The framing is configured as for the
+    /// original stream, but the prefix and preamble are not written
+    /// again, so the output can be appended to the document up to the
+    /// checkpoint. The statistics passed to a trailer count the items
+    /// and bytes before the checkpoint too, but a checksum covers only
+    /// the resumed output.
+    pub fn resume(mut self, checkpoint: &Checkpoint) -> Self {
+        // nothing was written before the default checkpoint.
+        if checkpoint.offset == 0 {
+            return self;
+        }
+        self.resumed = Some(match checkpoint.key {
+            Some(_) => State::NonEmpty,
+            None => State::Empty,
+        });
+        self.stats.byte_count = checkpoint.offset;
+        self.stats.item_count = checkpoint.item_count;
+        self
+    }
+    /// Continue a document at an offset, such as the start of a Range
+    /// request, after a checkpoint at or before it, e.g. from
+    /// Checkpoints::before(), or the default checkpoint to start over.
+    /// The output between the checkpoint and the
+    /// offset is not sent, so it can be appended to what the client has.
+    pub fn resume_at(mut self, checkpoint: &Checkpoint, offset: usize) -> Self {
+        self.skip = offset.saturating_sub(checkpoint.offset);
+        self.resume(checkpoint)
+    }
+}
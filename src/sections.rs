@@ -0,0 +1,222 @@
+use crate::{ByteStream, BytesWriter};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    stream::BoxStream,
+    task::{Context, Poll},
+    Stream, StreamExt, TryStream,
+};
+#[cfg(feature = "log")]
+use log::*;
+use std::{collections::VecDeque, pin::Pin};
+
+const SECTIONS_DEFAULT_BUFFER_SIZE: usize = 1 << 20;
+
+// a section whose stream has been started.
+struct Active<E> {
+    name: String,
+    stream: Option<BoxStream<'static, Result<Bytes, E>>>,
+    // chunks received before this section is the current one.
+    buffered: VecDeque<Bytes>,
+    buffered_bytes: usize,
+    // an error received before this section is the current one.
+    error: Option<E>,
+    // whether the name has been written.
+    started: bool,
+}
+
+/// A stream of a json object having a named member for each section,
+/// e.g. `{"totals":[...],"top":[...]}`, where each section is the
+/// output of a ByteStream, with its own serializer and framing. The
+/// sections are written in order. By default each one is run in turn,
+/// but with a concurrency above one, the following sections are run
+/// at the same time, and their output is buffered until it is their
+/// turn, up to a limit per section. An error is returned in turn too,
+/// after the output of the section that failed, and ends the stream.
+pub struct Sections<E> {
+    // sections that are not started yet.
+    waiting: VecDeque<(String, BoxStream<'static, Result<Bytes, E>>)>,
+    // started sections, of which the first is being written.
+    active: VecDeque<Active<E>>,
+    concurrency: usize,
+    // the most bytes buffered per section, beyond which it is not polled.
+    buffer_size: usize,
+    // the number of sections written so far.
+    written: usize,
+    done: bool,
+}
+
+impl<E> Default for Sections<E> {
+    fn default() -> Self {
+        Self {
+            waiting: VecDeque::new(),
+            active: VecDeque::new(),
+            concurrency: 1,
+            buffer_size: SECTIONS_DEFAULT_BUFFER_SIZE,
+            written: 0,
+            done: false,
+        }
+    }
+}
+
+impl<E: Send + 'static> Sections<E> {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Append a section having the given name.
+    pub fn section<InnerStream, InnerError, Serializer>(
+        mut self,
+        name: &str,
+        mut section: ByteStream<InnerStream, InnerError, Serializer, E>,
+    ) -> Self
+    where
+        InnerError: std::error::Error + 'static,
+        InnerStream: TryStream<Error = InnerError> + Send + 'static,
+        <InnerStream as TryStream>::Ok: Send,
+        E: From<InnerError> + std::error::Error,
+        Serializer: FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), E>
+            + Unpin
+            + Send
+            + 'static,
+    {
+        // the Sections warns instead, naming the section.
+        section.warn_on_drop = false;
+        self.waiting.push_back((name.to_string(), section.boxed()));
+        self
+    }
+    /// Set the maximum number of sections that run at the same time.
+    #[inline]
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+    /// Set how many bytes of a following section may be buffered, 1 MiB
+    /// by default. Once exceeded, the section is not polled again until
+    /// it is the current one.
+    #[inline]
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+    // start waiting sections, up to the concurrency.
+    fn start(&mut self) {
+        while self.active.len() < self.concurrency {
+            match self.waiting.pop_front() {
+                Some((name, stream)) => self.active.push_back(Active {
+                    name,
+                    stream: Some(stream),
+                    buffered: VecDeque::new(),
+                    buffered_bytes: 0,
+                    error: None,
+                    started: false,
+                }),
+                None => break,
+            }
+        }
+    }
+    // buffer the available output of the sections after the current
+    // one, up to the buffer size, and keep any error for their turn.
+    fn poll_following(&mut self, cx: &mut Context<'_>) {
+        let buffer_size = self.buffer_size;
+        for section in self.active.iter_mut().skip(1) {
+            while let Some(stream) = section.stream.as_mut() {
+                if section.buffered_bytes >= buffer_size {
+                    break;
+                }
+                match stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => {
+                        section.buffered_bytes += chunk.len();
+                        section.buffered.push_back(chunk);
+                    }
+                    Poll::Ready(Some(Err(e))) => {
+                        section.error = Some(e);
+                        section.stream = None;
+                    }
+                    Poll::Ready(None) => section.stream = None,
+                    Poll::Pending => break,
+                }
+            }
+        }
+    }
+    // prepend the name of the current section to its first chunk.
+    fn chunk(&mut self, chunk: Bytes) -> Bytes {
+        let section = &mut self.active[0];
+        if section.started {
+            return chunk;
+        }
+        section.started = true;
+        let name = serde_json::to_string(&section.name).unwrap_or_default();
+        let mut buf = BytesMut::with_capacity(name.len() + chunk.len() + 2);
+        buf.extend_from_slice(if self.written == 0 { b"{" } else { b"," });
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(b":");
+        buf.extend_from_slice(&chunk);
+        buf.freeze()
+    }
+}
+
+// the streams are boxed, and the errors are never pinned.
+impl<E> Unpin for Sections<E> {}
+
+impl<E: Send + 'static> Stream for Sections<E> {
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            this.start();
+            this.poll_following(cx);
+            let section = match this.active.front_mut() {
+                Some(section) => section,
+                None => {
+                    this.done = true;
+                    let end: &[u8] = if this.written == 0 { b"{}" } else { b"}" };
+                    return Poll::Ready(Some(Ok(Bytes::from_static(end))));
+                }
+            };
+            if let Some(chunk) = section.buffered.pop_front() {
+                section.buffered_bytes -= chunk.len();
+                return Poll::Ready(Some(Ok(this.chunk(chunk))));
+            }
+            if let Some(e) = section.error.take() {
+                this.done = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+            let stream = match section.stream.as_mut() {
+                Some(stream) => stream,
+                None => {
+                    this.active.pop_front();
+                    this.written += 1;
+                    continue;
+                }
+            };
+            match stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => return Poll::Ready(Some(Ok(this.chunk(chunk)))),
+                Poll::Ready(Some(Err(e))) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) => section.stream = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl<E> Drop for Sections<E> {
+    fn drop(&mut self) {
+        if !self.done {
+            match self.active.front() {
+                Some(section) => warn!(
+                    "dropped Sections in section {:?}, after {} sections",
+                    section.name, self.written
+                ),
+                None => warn!("dropped Sections after {} sections", self.written),
+            }
+        }
+    }
+}
@@ -1,10 +1,14 @@
 use futures::{
+    future::{BoxFuture, LocalBoxFuture},
     prelude::*,
     stream::BoxStream,
     task::{Context, Poll},
 };
+// the crate root also re-exports it from bytestream.
+use pin_project::pin_project;
+#[allow(unused_imports)]
 pub use std::io::Write;
-use std::pin::Pin;
+use std::{marker::PhantomPinned, pin::Pin};
 
 #[ouroboros::self_referencing]
 pub struct SelfRefStream<Args: 'static, Item, Error> {
@@ -26,6 +30,55 @@ impl<Args: 'static, Item, Error> SelfRefStream<Args, Item, Error> {
         }
         .build()
     }
+    /// Like build, but the inner builder is async, so that setup which
+    /// borrows the args, such as a permission check, can be awaited
+    /// before the stream starts. A setup error is returned before any
+    /// response is sent. The returned future is not Send, which actix
+    /// handlers do not require.
+    #[inline]
+    pub async fn try_build_async<SetupError>(
+        args: Args,
+        inner_builder: impl for<'this> FnOnce(
+            &'this Args,
+        ) -> LocalBoxFuture<
+            'this,
+            Result<BoxStream<'this, Result<Item, Error>>, SetupError>,
+        >,
+    ) -> Result<Self, SetupError> {
+        SelfRefStreamAsyncTryBuilder {
+            args,
+            inner_builder,
+        }
+        .try_build()
+        .await
+    }
+    /// Borrow the args, e.g. while the stream is running.
+    #[inline]
+    pub fn args(&self) -> &Args {
+        self.borrow_args()
+    }
+    /// Drop the inner stream, which may be partially read, and return
+    /// the owned args.
+    #[inline]
+    pub fn into_args(self) -> Args {
+        self.into_heads().args
+    }
+    /// Run a hook with the owned args once the inner stream has ended.
+    #[inline]
+    pub fn on_end<F>(self, hook: F) -> OnEnd<Self, Error, F>
+    where
+        F: FnOnce(Args) -> BoxFuture<'static, Result<(), Error>>,
+    {
+        OnEnd::new(self, hook)
+    }
+}
+
+impl<Args: 'static, Item, Error> IntoArgs for SelfRefStream<Args, Item, Error> {
+    type Args = Args;
+    #[inline]
+    fn into_args(self) -> Args {
+        self.into_args()
+    }
 }
 
 impl<Args: 'static, Item, Error> Stream for SelfRefStream<Args, Item, Error> {
@@ -36,3 +89,233 @@ impl<Args: 'static, Item, Error> Stream for SelfRefStream<Args, Item, Error> {
         self.with_inner_mut(|s| s.as_mut().poll_next(cx))
     }
 }
+
+/// Like SelfRefStream, but the inner stream borrows the args mutably,
+/// as needed to fetch from a connection or a transaction.
+#[ouroboros::self_referencing]
+pub struct SelfRefStreamMut<Args: 'static, Item, Error> {
+    args: Args,
+    #[borrows(mut args)]
+    #[covariant] // Box is covariant.
+    inner: BoxStream<'this, Result<Item, Error>>,
+}
+
+impl<Args: 'static, Item, Error> SelfRefStreamMut<Args, Item, Error> {
+    #[inline]
+    pub fn build(
+        args: Args,
+        inner_builder: impl for<'this> FnOnce(&'this mut Args) -> BoxStream<'this, Result<Item, Error>>,
+    ) -> Self {
+        SelfRefStreamMutBuilder {
+            args,
+            inner_builder,
+        }
+        .build()
+    }
+    /// Drop the inner stream, which may be partially read, and return
+    /// the owned args, e.g. to reuse a connection.
+    #[inline]
+    pub fn into_args(self) -> Args {
+        self.into_heads().args
+    }
+    /// Run a hook with the owned args once the inner stream has ended,
+    /// e.g. to commit a transaction after all rows are sent.
+    #[inline]
+    pub fn on_end<F>(self, hook: F) -> OnEnd<Self, Error, F>
+    where
+        F: FnOnce(Args) -> BoxFuture<'static, Result<(), Error>>,
+    {
+        OnEnd::new(self, hook)
+    }
+}
+
+impl<Args: 'static, Item, Error> IntoArgs for SelfRefStreamMut<Args, Item, Error> {
+    type Args = Args;
+    #[inline]
+    fn into_args(self) -> Args {
+        self.into_args()
+    }
+}
+
+impl<Args: 'static, Item, Error> Stream for SelfRefStreamMut<Args, Item, Error> {
+    type Item = Result<Item, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.with_inner_mut(|s| s.as_mut().poll_next(cx))
+    }
+}
+
+/// A stream that owns its args, and can return them.
+pub trait IntoArgs {
+    type Args;
+    /// Drop the stream and return the owned args.
+    fn into_args(self) -> Self::Args;
+}
+
+/// A stream that runs a hook with the owned args once the inner stream
+/// has ended, and ends when the hook's future completes. The hook is
+/// not run if the stream is dropped early, e.g. when the client
+/// disconnects, so that a transaction is rolled back.
+pub struct OnEnd<S: IntoArgs, Error, F> {
+    stream: Option<S>,
+    hook: Option<F>,
+    end: Option<BoxFuture<'static, Result<(), Error>>>,
+}
+
+impl<S: IntoArgs, Error, F> OnEnd<S, Error, F>
+where
+    F: FnOnce(S::Args) -> BoxFuture<'static, Result<(), Error>>,
+{
+    #[inline]
+    pub fn new(stream: S, hook: F) -> Self {
+        Self {
+            stream: Some(stream),
+            hook: Some(hook),
+            end: None,
+        }
+    }
+}
+
+impl<S, Item, Error, F> Stream for OnEnd<S, Error, F>
+where
+    S: IntoArgs + Stream<Item = Result<Item, Error>> + Unpin,
+    F: FnOnce(S::Args) -> BoxFuture<'static, Result<(), Error>> + Unpin,
+{
+    type Item = Result<Item, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(end) = self.end.as_mut() {
+                let result = futures::ready!(end.as_mut().poll(cx));
+                self.end = None;
+                return Poll::Ready(result.err().map(Err));
+            }
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Poll::Ready(None),
+            };
+            match futures::ready!(stream.poll_next_unpin(cx)) {
+                Some(item) => return Poll::Ready(Some(item)),
+                None => {
+                    // drop the inner stream, releasing the borrow of args.
+                    let args = self.stream.take().map(IntoArgs::into_args);
+                    if let (Some(args), Some(hook)) = (args, self.hook.take()) {
+                        self.end = Some(hook(args));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds an inner stream that borrows the args, for
+/// UnboxedSelfRefStream. It is implemented for any function of the
+/// args returning a stream, typically a fn item such as
+/// `fn rows(args: &Args) -> impl Stream<Item = ..> + '_`, or one
+/// returning sqlx's `query_as(..).fetch(..)`. A closure works too, if
+/// its stream does not borrow the args, since the return type of a
+/// closure cannot borrow its argument.
+pub trait InnerBuilder<'a, Args: 'a, Item, Error> {
+    type Stream: Stream<Item = Result<Item, Error>> + 'a;
+    fn build(self, args: &'a Args) -> Self::Stream;
+}
+
+impl<'a, Args: 'a, Item, Error, F, S> InnerBuilder<'a, Args, Item, Error> for F
+where
+    F: FnOnce(&'a Args) -> S,
+    S: Stream<Item = Result<Item, Error>> + 'a,
+{
+    type Stream = S;
+    #[inline]
+    fn build(self, args: &'a Args) -> S {
+        self(args)
+    }
+}
+
+/// A SelfRefStream that stores the args and the concrete inner stream
+/// inline, rather than boxing each of them, avoiding the allocations
+/// per stream and dynamic dispatch per poll. The inner stream is built
+/// on the first poll, once this is pinned, and is polled in place, so
+/// it need not be Unpin. This is not Unpin, so pin it in a Box to peek
+/// it with PeekedStream.
+#[pin_project]
+pub struct UnboxedSelfRefStream<Args, Item, Error, Builder>
+where
+    Args: 'static,
+    Builder: for<'a> InnerBuilder<'a, Args, Item, Error>,
+{
+    // declared before the args, so that it is dropped first.
+    #[pin]
+    inner: Option<<Builder as InnerBuilder<'static, Args, Item, Error>>::Stream>,
+    builder: Option<Builder>,
+    args: Args,
+    // the inner stream borrows the args, so they must not move.
+    #[pin]
+    pinned: PhantomPinned,
+}
+
+impl<Args, Item, Error, Builder> UnboxedSelfRefStream<Args, Item, Error, Builder>
+where
+    Args: 'static,
+    Builder: for<'a> InnerBuilder<'a, Args, Item, Error>,
+{
+    #[inline]
+    pub fn build(args: Args, builder: Builder) -> Self {
+        Self {
+            inner: None,
+            builder: Some(builder),
+            args,
+            pinned: PhantomPinned,
+        }
+    }
+    /// Borrow the args, e.g. while the stream is running.
+    #[inline]
+    pub fn args(&self) -> &Args {
+        &self.args
+    }
+    /// Return the owned args. The inner stream has not been built,
+    /// since this has not been pinned.
+    #[inline]
+    pub fn into_args(self) -> Args {
+        self.args
+    }
+}
+
+impl<Args, Item, Error, Builder> IntoArgs for UnboxedSelfRefStream<Args, Item, Error, Builder>
+where
+    Args: 'static,
+    Builder: for<'a> InnerBuilder<'a, Args, Item, Error>,
+{
+    type Args = Args;
+    #[inline]
+    fn into_args(self) -> Args {
+        self.into_args()
+    }
+}
+
+impl<Args, Item, Error, Builder> Stream for UnboxedSelfRefStream<Args, Item, Error, Builder>
+where
+    Args: 'static,
+    Builder: for<'a> InnerBuilder<'a, Args, Item, Error>,
+{
+    type Item = Result<Item, Error>;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        if let Some(builder) = this.builder.take() {
+            // SAFETY: this is pinned and not Unpin, so the args stay in
+            // place until this is dropped, and the inner stream is
+            // dropped before them. The builder is generic over the
+            // lifetime of the borrow, so it cannot rely on it being
+            // 'static.
+            let args: &'static Args = unsafe { &*(this.args as *const Args) };
+            this.inner.set(Some(builder.build(args)));
+        }
+        match this.inner.as_pin_mut() {
+            Some(inner) => inner.poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
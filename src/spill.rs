@@ -0,0 +1,294 @@
+use bytes::{Bytes, BytesMut};
+use futures::{
+    task::{Context, Poll, Waker},
+    Future, Stream,
+};
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+// the largest chunk read back from the file.
+const SPILL_READ_SIZE: usize = 64 * 1024;
+
+/// The counters of a spilled stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpillStats {
+    /// The most bytes held in memory at once.
+    pub peak_memory: usize,
+    /// Number of bytes written to the temporary file.
+    pub spilled_bytes: u64,
+    /// Number of chunks written to the temporary file.
+    pub spilled_chunks: usize,
+    /// True once the inner stream is done, and its query released.
+    pub drained: bool,
+}
+
+// an unlinked temporary file, which is removed when closed. It has its
+// own lock, so that the state is not locked during its IO.
+struct SpillFile {
+    file: Arc<Mutex<File>>,
+    written: u64,
+    read: u64,
+}
+
+struct SpillState<E> {
+    memory: VecDeque<Bytes>,
+    memory_bytes: usize,
+    // the chunks after the memory ones, once the threshold is reached.
+    file: Option<SpillFile>,
+    error: Option<E>,
+    done: bool,
+    reader_dropped: bool,
+    // set when the file cannot be read, which ends the stream.
+    failed: bool,
+    reader: Option<Waker>,
+    stats: SpillStats,
+}
+
+impl<E> SpillState<E> {
+    // whether the driver should stop reading the inner stream.
+    #[inline]
+    fn stopped(&self) -> bool {
+        self.reader_dropped || self.failed
+    }
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+// hold a chunk in memory, or append it to the file, by the driver.
+fn push<E>(state: &Mutex<SpillState<E>>, chunk: Bytes, threshold: usize) -> io::Result<()> {
+    let spill = {
+        let mut state = state.lock().unwrap();
+        // return to memory once the file has been read.
+        if matches!(&state.file, Some(spill) if spill.read == spill.written) {
+            state.file = None;
+        }
+        if state.file.is_none() && state.memory_bytes + chunk.len() <= threshold {
+            state.memory_bytes += chunk.len();
+            state.stats.peak_memory = state.stats.peak_memory.max(state.memory_bytes);
+            state.memory.push_back(chunk);
+            return Ok(());
+        }
+        state
+            .file
+            .as_ref()
+            .map(|spill| (spill.file.clone(), spill.written))
+    };
+    let (file, offset) = match spill {
+        Some(spill) => spill,
+        None => {
+            let file = Arc::new(Mutex::new(tempfile::tempfile()?));
+            state.lock().unwrap().file = Some(SpillFile {
+                file: file.clone(),
+                written: 0,
+                read: 0,
+            });
+            (file, 0)
+        }
+    };
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&chunk)?;
+    }
+    let mut state = state.lock().unwrap();
+    // the reader may have dropped the file meanwhile.
+    if let Some(spill) = state.file.as_mut() {
+        if Arc::ptr_eq(&spill.file, &file) {
+            spill.written += chunk.len() as u64;
+        }
+    }
+    state.stats.spilled_bytes += chunk.len() as u64;
+    state.stats.spilled_chunks += 1;
+    Ok(())
+}
+
+// read a chunk of the file at an offset, by the reader.
+fn read_at(file: &Mutex<File>, offset: u64, len: usize) -> io::Result<Bytes> {
+    let mut buf = BytesMut::zeroed(len);
+    let mut file = file.lock().unwrap();
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf.freeze())
+}
+
+/// Buffer the output of a stream, e.g. a ByteStream, so that a query
+/// can be read as fast as the database returns it, and its connection
+/// released, even when the client is slow. Up to `threshold` bytes are
+/// held in memory, and the rest in a temporary file, which is removed
+/// once the stream ends or is dropped.
+///
+/// Returns the stream to respond with, and a driver that reads the
+/// inner stream, which must be spawned, e.g. by actix_web::rt::spawn.
+pub fn spill<S, E>(inner_stream: S, threshold: usize) -> (SpillStream<E>, SpillDriver<S, E>)
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: From<io::Error>,
+{
+    let state = Arc::new(Mutex::new(SpillState {
+        memory: VecDeque::new(),
+        memory_bytes: 0,
+        file: None,
+        error: None,
+        done: false,
+        reader_dropped: false,
+        failed: false,
+        reader: None,
+        stats: SpillStats::default(),
+    }));
+    (
+        SpillStream {
+            state: state.clone(),
+        },
+        SpillDriver {
+            inner_stream: Some(inner_stream),
+            state,
+            threshold,
+        },
+    )
+}
+
+/// Reads the inner stream of spill() into memory and the temporary file.
+#[pin_project]
+pub struct SpillDriver<S, E> {
+    // None once done, to release the query.
+    #[pin]
+    inner_stream: Option<S>,
+    state: Arc<Mutex<SpillState<E>>>,
+    threshold: usize,
+}
+
+impl<S, E> SpillDriver<S, E> {
+    pub fn stats(&self) -> SpillStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl<S, E> Future for SpillDriver<S, E>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: From<io::Error>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = self.project();
+        loop {
+            let inner_stream = match this.inner_stream.as_mut().as_pin_mut() {
+                // stop reading once the client has gone or failed.
+                Some(_) if this.state.lock().unwrap().stopped() => {
+                    this.inner_stream.set(None);
+                    return Poll::Ready(());
+                }
+                Some(inner_stream) => inner_stream,
+                None => return Poll::Ready(()),
+            };
+            let mut state = match inner_stream.poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    // the chunk is written without holding the lock.
+                    let pushed = push(this.state, chunk, *this.threshold);
+                    let mut state = this.state.lock().unwrap();
+                    if let Err(e) = pushed {
+                        state.error = Some(e.into());
+                        state.done = true;
+                    }
+                    state
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    let mut state = this.state.lock().unwrap();
+                    state.error = Some(e);
+                    state.done = true;
+                    state
+                }
+                Poll::Ready(None) => {
+                    let mut state = this.state.lock().unwrap();
+                    state.done = true;
+                    state
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            if state.done {
+                state.stats.drained = true;
+                this.inner_stream.set(None);
+            }
+            state.wake_reader();
+        }
+    }
+}
+
+/// The output of spill(), served from memory and the temporary file.
+pub struct SpillStream<E> {
+    state: Arc<Mutex<SpillState<E>>>,
+}
+
+impl<E> SpillStream<E> {
+    pub fn stats(&self) -> SpillStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl<E: From<io::Error>> Stream for SpillStream<E> {
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (file, offset, len) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(chunk) = state.memory.pop_front() {
+                state.memory_bytes -= chunk.len();
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            match state.file.as_ref() {
+                Some(spill) if spill.read < spill.written => {
+                    let len = (spill.written - spill.read).min(SPILL_READ_SIZE as u64);
+                    (spill.file.clone(), spill.read, len as usize)
+                }
+                // the error follows the chunks before it.
+                _ if state.done || state.failed => {
+                    state.file = None;
+                    return Poll::Ready(state.error.take().map(Err));
+                }
+                _ => {
+                    state.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        // the chunk is read without holding the lock.
+        let read = read_at(&file, offset, len);
+        let mut state = self.state.lock().unwrap();
+        match read {
+            Ok(chunk) => {
+                if let Some(spill) = state.file.as_mut() {
+                    spill.read += len as u64;
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            // the later chunks would follow a gap, so the stream ends.
+            Err(e) => {
+                state.failed = true;
+                state.error = None;
+                state.memory.clear();
+                state.memory_bytes = 0;
+                state.file = None;
+                Poll::Ready(Some(Err(e.into())))
+            }
+        }
+    }
+}
+
+impl<E> Drop for SpillStream<E> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.reader_dropped = true;
+        state.memory.clear();
+        state.file = None;
+    }
+}
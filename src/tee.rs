@@ -0,0 +1,556 @@
+use crate::{conditional::if_none_match_matches, util::to_hex, ByteStream, BytesWriter};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    stream::{self, BoxStream},
+    task::{Context, Poll},
+    Stream, StreamExt, TryStream,
+};
+#[cfg(feature = "log")]
+use log::*;
+use pin_project::{pin_project, pinned_drop};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Where a Tee copies the chunks of a stream, e.g. a cache entry. The
+/// copy is committed only if the stream completes.
+pub trait TeeSink: Send {
+    fn write(&mut self, chunk: &Bytes) -> io::Result<()>;
+    /// The stream is complete: make the copy available.
+    fn commit(self: Box<Self>) -> io::Result<()>;
+    /// The stream failed, or was dropped: discard the copy.
+    fn abort(self: Box<Self>);
+}
+
+/// Copies each chunk of a stream to a sink while passing it on. A
+/// failing sink is aborted, but does not fail the stream.
+#[pin_project(PinnedDrop)]
+pub struct Tee<S> {
+    #[pin]
+    inner_stream: S,
+    sink: Option<Box<dyn TeeSink>>,
+    // set by a ByteStream whose error was written by on_error().
+    failed: Option<Arc<AtomicBool>>,
+}
+
+impl<S> Tee<S> {
+    pub fn new(inner_stream: S, sink: Box<dyn TeeSink>) -> Self {
+        Self {
+            inner_stream,
+            sink: Some(sink),
+            failed: None,
+        }
+    }
+}
+
+impl<S, E> Stream for Tee<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let poll = this.inner_stream.poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(sink) = this.sink.as_mut() {
+                    if let Err(_e) = sink.write(chunk) {
+                        #[cfg(feature = "log")]
+                        warn!("tee: failed to write: {:?}", _e);
+                        this.sink.take().unwrap().abort();
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => {
+                if let Some(sink) = this.sink.take() {
+                    sink.abort();
+                }
+            }
+            Poll::Ready(None) => match this.sink.take() {
+                // on_error() wrote the error, and ended the stream.
+                Some(sink) if matches!(this.failed, Some(failed) if failed.load(Ordering::Acquire)) => {
+                    sink.abort()
+                }
+                Some(sink) => {
+                    if let Err(_e) = sink.commit() {
+                        #[cfg(feature = "log")]
+                        warn!("tee: failed to commit: {:?}", _e);
+                    }
+                }
+                None => (),
+            },
+            Poll::Pending => (),
+        }
+        poll
+    }
+}
+
+#[pinned_drop]
+impl<S> PinnedDrop for Tee<S> {
+    fn drop(self: Pin<&mut Self>) {
+        if let Some(sink) = self.project().sink.take() {
+            sink.abort();
+        }
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Copy the output to a sink, e.g. `cache.sink(key)`. The copy is
+    /// aborted if the stream fails, even when on_error() writes the
+    /// error and ends the stream normally. The ETag is known only once
+    /// the stream completes, so this response has none; the responses
+    /// from the cached copy have it.
+    #[inline]
+    pub fn tee(mut self, sink: Box<dyn TeeSink>) -> Tee<Self> {
+        let failed = self.failed.get_or_insert_with(Default::default).clone();
+        Tee {
+            inner_stream: self,
+            sink: Some(sink),
+            failed: Some(failed),
+        }
+    }
+}
+
+/// A cached copy of a complete stream.
+pub struct Cached {
+    /// The strong ETag, quoted, of the content.
+    pub etag: String,
+    body: CachedBody,
+}
+
+enum CachedBody {
+    Memory(Arc<Vec<Bytes>>),
+    File(File),
+}
+
+// the size of a chunk read from a cached file.
+const CACHED_READ_SIZE: usize = 64 * 1024;
+
+impl Cached {
+    /// True if an If-None-Match header names this ETag, so that the
+    /// response can be 304 Not Modified.
+    pub fn matches(&self, if_none_match: Option<&str>) -> bool {
+        if_none_match_matches(if_none_match, &self.etag)
+    }
+    /// The response to a request having the If-None-Match header, if
+    /// any: 304 Not Modified if it names this ETag, or else the content.
+    pub fn respond(self, if_none_match: Option<&str>) -> CachedResponse {
+        if self.matches(if_none_match) {
+            CachedResponse::NotModified { etag: self.etag }
+        } else {
+            CachedResponse::Content {
+                etag: self.etag.clone(),
+                body: self.stream(),
+            }
+        }
+    }
+    /// The content, in chunks. A FileCache copy is read from the file
+    /// as each chunk is polled, on the thread polling it, which is
+    /// usually served from the page cache but may block on a slow disk.
+    pub fn stream(self) -> BoxStream<'static, io::Result<Bytes>> {
+        match self.body {
+            CachedBody::Memory(chunks) => {
+                stream::iter((0..chunks.len()).map(move |i| Ok(chunks[i].clone()))).boxed()
+            }
+            CachedBody::File(file) => stream::unfold(Some(file), |file| async move {
+                let mut file = file?;
+                let mut buf = BytesMut::zeroed(CACHED_READ_SIZE);
+                match file.read(&mut buf) {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(buf.freeze()), Some(file)))
+                    }
+                    Err(e) => Some((Err(e), None)),
+                }
+            })
+            .boxed(),
+        }
+    }
+}
+
+/// How to answer a request from a Cached copy.
+pub enum CachedResponse {
+    /// Respond with 304 Not Modified, and the ETag header.
+    NotModified { etag: String },
+    /// Respond with the ETag header and the content.
+    Content {
+        etag: String,
+        body: BoxStream<'static, io::Result<Bytes>>,
+    },
+}
+
+impl CachedResponse {
+    #[inline]
+    pub fn etag(&self) -> &str {
+        match self {
+            CachedResponse::NotModified { etag } | CachedResponse::Content { etag, .. } => etag,
+        }
+    }
+}
+
+fn etag(sha256: Sha256) -> String {
+    format!("\"{}\"", to_hex(&sha256.finalize()))
+}
+
+/// A cache of complete streams, by key, e.g. the report name and its
+/// parameters. The ETag of a stream is computed while it is copied, so
+/// only the responses from a cached copy have an ETag, not the first
+/// response, which is copied.
+pub trait ArtifactCache {
+    /// A sink that stores a stream under the key once it completes.
+    fn sink(&self, key: &str) -> Box<dyn TeeSink>;
+    /// The cached copy, if any, and not expired.
+    fn get(&self, key: &str) -> Option<Cached>;
+}
+
+/// Caches streams as files in a directory. Each file is written under a
+/// temporary name, and renamed once the stream completes. The ETag is
+/// written in a header of the file, so that it is renamed along with
+/// the content. Expired files are removed when requested, and by
+/// cleanup(), which should be run periodically if max_age is set.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    dir: PathBuf,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+}
+
+impl FileCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_age: None,
+            max_size: None,
+        }
+    }
+    /// Expire files older than max_age.
+    #[inline]
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+    /// Keep the total size of the files under max_size bytes, by
+    /// removing the oldest files after each commit.
+    #[inline]
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+    // the file name is a hash of the key, which may have any characters.
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.dir
+            .join(to_hex(&Sha256::digest(key.as_bytes())))
+            .with_extension(extension)
+    }
+    fn expired(&self, modified: SystemTime) -> bool {
+        match self.max_age {
+            Some(max_age) => SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > max_age),
+            None => false,
+        }
+    }
+    /// Remove the expired files, and the temporary files older than
+    /// max_age, which a crashed process may have left, and then the
+    /// oldest files until their total size is under max_size.
+    pub fn cleanup(&self) -> io::Result<()> {
+        // (modified, size, path) of the files that are kept.
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let cached = match path.extension().and_then(|e| e.to_str()) {
+                Some("data") => true,
+                Some("tmp") => false,
+                _ => continue,
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // removed meanwhile.
+                Err(_) => continue,
+            };
+            let modified = metadata.modified()?;
+            if self.expired(modified) {
+                fs::remove_file(&path).ok();
+            } else if cached {
+                files.push((modified, metadata.len(), path));
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+            files.sort();
+            for (_, len, path) in files {
+                if size <= max_size {
+                    break;
+                }
+                fs::remove_file(&path).ok();
+                size -= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+// the header of a cached file: its ETag, of a fixed length, and a newline.
+const FILE_HEADER_SIZE: usize = 67;
+
+// the temporary file is removed when the sink is dropped, unless it
+// was committed.
+struct FileSink {
+    cache: FileCache,
+    file: File,
+    tmp: PathBuf,
+    path: PathBuf,
+    sha256: Sha256,
+    committed: bool,
+}
+
+impl TeeSink for FileSink {
+    fn write(&mut self, chunk: &Bytes) -> io::Result<()> {
+        self.sha256.update(chunk);
+        self.file.write_all(chunk)
+    }
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let etag = etag(std::mem::take(&mut self.sha256));
+        self.file.write_all(format!("{}\n", etag).as_bytes())?;
+        self.file.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        if self.cache.max_size.is_some() {
+            if let Err(_e) = self.cache.cleanup() {
+                #[cfg(feature = "log")]
+                warn!("tee: failed to clean up {:?}: {:?}", self.cache.dir, _e);
+            }
+        }
+        Ok(())
+    }
+    fn abort(self: Box<Self>) {}
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if !self.committed {
+            fs::remove_file(&self.tmp).ok();
+        }
+    }
+}
+
+// a sink that failed to open, which discards the stream.
+struct NullSink;
+
+impl TeeSink for NullSink {
+    fn write(&mut self, _: &Bytes) -> io::Result<()> {
+        Ok(())
+    }
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+    fn abort(self: Box<Self>) {}
+}
+
+impl ArtifactCache for FileCache {
+    fn sink(&self, key: &str) -> Box<dyn TeeSink> {
+        // a unique temporary name, since several streams may write a key.
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.path(key, &format!("{}-{}.tmp", std::process::id(), n));
+        // the header is written once the ETag is known.
+        let file = File::create(&tmp).and_then(|mut file| {
+            file.write_all(&[b' '; FILE_HEADER_SIZE])?;
+            Ok(file)
+        });
+        match file {
+            Ok(file) => Box::new(FileSink {
+                cache: self.clone(),
+                file,
+                tmp,
+                path: self.path(key, "data"),
+                sha256: Sha256::new(),
+                committed: false,
+            }),
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                warn!("tee: failed to create {:?}: {:?}", tmp, _e);
+                fs::remove_file(&tmp).ok();
+                Box::new(NullSink)
+            }
+        }
+    }
+    fn get(&self, key: &str) -> Option<Cached> {
+        let path = self.path(key, "data");
+        let mut file = File::open(&path).ok()?;
+        let modified = file.metadata().and_then(|m| m.modified()).ok()?;
+        if self.expired(modified) {
+            fs::remove_file(&path).ok();
+            return None;
+        }
+        // the content follows the header.
+        let mut header = [0; FILE_HEADER_SIZE];
+        file.read_exact(&mut header).ok()?;
+        let etag = std::str::from_utf8(&header).ok()?.strip_suffix('\n')?;
+        Some(Cached {
+            etag: etag.to_string(),
+            body: CachedBody::File(file),
+        })
+    }
+}
+
+struct MemoryEntry {
+    chunks: Arc<Vec<Bytes>>,
+    size: usize,
+    etag: String,
+    created: Instant,
+    // the tick of the last use, for eviction.
+    used: u64,
+}
+
+#[derive(Default)]
+struct MemoryCacheState {
+    entries: HashMap<String, MemoryEntry>,
+    size: usize,
+    tick: u64,
+}
+
+/// Caches streams in memory, up to a total size in bytes, evicting the
+/// least recently used.
+#[derive(Clone)]
+pub struct MemoryCache {
+    state: Arc<Mutex<MemoryCacheState>>,
+    capacity: usize,
+    max_age: Option<Duration>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Default::default(),
+            capacity,
+            max_age: None,
+        }
+    }
+    /// Ignore entries older than max_age.
+    #[inline]
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+    /// The total size of the entries.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn insert(&self, key: String, chunks: Vec<Bytes>, size: usize, etag: String) {
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.remove(&key) {
+            state.size -= old.size;
+        }
+        while state.size + size > self.capacity {
+            let lru = match state.entries.iter().min_by_key(|(_, entry)| entry.used) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            let entry = state.entries.remove(&lru).unwrap();
+            state.size -= entry.size;
+        }
+        state.tick += 1;
+        let entry = MemoryEntry {
+            chunks: Arc::new(chunks),
+            size,
+            etag,
+            created: Instant::now(),
+            used: state.tick,
+        };
+        state.size += size;
+        state.entries.insert(key, entry);
+    }
+}
+
+struct MemorySink {
+    cache: MemoryCache,
+    key: String,
+    chunks: Vec<Bytes>,
+    size: usize,
+    sha256: Sha256,
+}
+
+impl TeeSink for MemorySink {
+    fn write(&mut self, chunk: &Bytes) -> io::Result<()> {
+        self.size += chunk.len();
+        // stop copying once the stream cannot fit.
+        if self.size > self.cache.capacity {
+            return Err(io::Error::other("larger than the cache"));
+        }
+        self.sha256.update(chunk);
+        self.chunks.push(chunk.clone());
+        Ok(())
+    }
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let this = *self;
+        this.cache
+            .insert(this.key, this.chunks, this.size, etag(this.sha256));
+        Ok(())
+    }
+    fn abort(self: Box<Self>) {}
+}
+
+impl ArtifactCache for MemoryCache {
+    fn sink(&self, key: &str) -> Box<dyn TeeSink> {
+        Box::new(MemorySink {
+            cache: self.clone(),
+            key: key.to_string(),
+            chunks: Vec::new(),
+            size: 0,
+            sha256: Sha256::new(),
+        })
+    }
+    fn get(&self, key: &str) -> Option<Cached> {
+        let mut state = self.state.lock().unwrap();
+        let expired = match (state.entries.get(key), self.max_age) {
+            (None, _) => return None,
+            (Some(entry), Some(max_age)) => entry.created.elapsed() > max_age,
+            (Some(_), None) => false,
+        };
+        if expired {
+            let entry = state.entries.remove(key).unwrap();
+            state.size -= entry.size;
+            return None;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = state.entries.get_mut(key).unwrap();
+        entry.used = tick;
+        Some(Cached {
+            etag: entry.etag.clone(),
+            body: CachedBody::Memory(entry.chunks.clone()),
+        })
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{ByteStream, BytesWriter};
+use bytes::Bytes;
+use futures::{
+    task::{Context, Poll},
+    Future, Stream, TryStream,
+};
+use futures_timer::Delay;
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct TokenBucket {
+    bytes_per_second: f64,
+    burst: f64,
+    // negative once a chunk larger than the available tokens is sent.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_second).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// A token bucket limit in bytes per second, which can be shared by
+/// several streams, e.g. all the downloads of one client.
+#[derive(Debug, Clone)]
+pub struct RateLimit(Arc<Mutex<TokenBucket>>);
+
+impl RateLimit {
+    /// Allow on average `bytes_per_second`, and up to `burst` bytes at
+    /// once after an idle period.
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        Self(Arc::new(Mutex::new(TokenBucket {
+            bytes_per_second: bytes_per_second.max(1) as f64,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        })))
+    }
+    // take the tokens of a chunk that was sent.
+    fn take(&self, bytes: usize) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill();
+        bucket.tokens -= bytes as f64;
+    }
+    // how long to wait until the tokens taken have been refilled.
+    fn wait(&self) -> Option<Duration> {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill();
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                -bucket.tokens / bucket.bytes_per_second,
+            ))
+        }
+    }
+}
+
+/// The rate limits of each client, keyed e.g. by an API key taken from
+/// the request, so that every stream of a client shares one limit.
+#[derive(Debug, Clone)]
+pub struct RateLimits<K> {
+    limits: Arc<Mutex<HashMap<K, RateLimit>>>,
+    bytes_per_second: u64,
+    burst: u64,
+}
+
+impl<K: Eq + Hash> RateLimits<K> {
+    /// The limit given to each client.
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        Self {
+            limits: Default::default(),
+            bytes_per_second,
+            burst,
+        }
+    }
+    /// The limit of a client, shared with its other streams.
+    pub fn get(&self, client: K) -> RateLimit {
+        let mut limits = self.limits.lock().unwrap();
+        // forget the clients that have no stream left.
+        limits.retain(|_, limit| Arc::strong_count(&limit.0) > 1);
+        limits
+            .entry(client)
+            .or_insert_with(|| RateLimit::new(self.bytes_per_second, self.burst))
+            .clone()
+    }
+}
+
+/// Limits the rate of a stream of chunks. Once a chunk has used up the
+/// tokens, the inner stream is not polled until they are refilled, so
+/// that the rows are not read any faster than they can be sent.
+#[pin_project]
+pub struct Throttle<S> {
+    #[pin]
+    inner_stream: S,
+    limit: RateLimit,
+    delay: Option<Delay>,
+}
+
+impl<S> Throttle<S> {
+    pub fn new(inner_stream: S, limit: RateLimit) -> Self {
+        Self {
+            inner_stream,
+            limit,
+            delay: None,
+        }
+    }
+}
+
+impl<S, E> Stream for Throttle<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                if Pin::new(delay).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                *this.delay = None;
+            }
+            // other streams sharing the limit may have taken tokens.
+            match this.limit.wait() {
+                Some(wait) => *this.delay = Some(Delay::new(wait)),
+                None => break,
+            }
+        }
+        let poll = this.inner_stream.poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.limit.take(chunk.len());
+        }
+        poll
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Limit the rate of the response.
+    #[inline]
+    pub fn throttle(self, limit: RateLimit) -> Throttle<Self> {
+        Throttle::new(self, limit)
+    }
+}
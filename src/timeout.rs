@@ -0,0 +1,200 @@
+use crate::{ByteStream, BytesWriter, StreamStats};
+use futures::{
+    task::{Context, Poll},
+    Future, TryStream,
+};
+use futures_timer::Delay;
+#[cfg(feature = "log")]
+use log::*;
+use std::{
+    fmt,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+/// Which timeout expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The query returned no row in time.
+    FirstRow,
+    /// The query stalled between rows.
+    Idle,
+    /// The client did not read the last chunk in time.
+    ClientGap,
+    /// The response took too long overall.
+    Total,
+}
+
+impl TimeoutKind {
+    /// The kind, e.g. for the error code in a document.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutKind::FirstRow => "first_row_timeout",
+            TimeoutKind::Idle => "idle_timeout",
+            TimeoutKind::ClientGap => "client_timeout",
+            TimeoutKind::Total => "total_timeout",
+        }
+    }
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The error when a timeout of a ByteStream expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamTimeout {
+    pub kind: TimeoutKind,
+    /// The configured timeout.
+    pub after: Duration,
+}
+
+impl fmt::Display for StreamTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {:?}", self.kind, self.after)
+    }
+}
+
+impl std::error::Error for StreamTimeout {}
+
+/// The timeouts of a ByteStream. None are set by default.
+///
+/// The client gap and total timers stay registered with the task while
+/// a chunk is unread, so a client that stops reading still times out:
+/// the task is woken when they expire, and the stream drops the query
+/// when it is next polled, ending the response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub first_row: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub client_gap: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The longest wait for the first row of the query.
+    #[inline]
+    pub fn first_row(mut self, timeout: Duration) -> Self {
+        self.first_row = Some(timeout);
+        self
+    }
+    /// The longest wait for each later row of the query.
+    #[inline]
+    pub fn idle(mut self, timeout: Duration) -> Self {
+        self.idle = Some(timeout);
+        self
+    }
+    /// The longest time the client may take to ask for the next chunk,
+    /// having received the previous one.
+    #[inline]
+    pub fn client_gap(mut self, timeout: Duration) -> Self {
+        self.client_gap = Some(timeout);
+        self
+    }
+    /// The longest time from the first poll to the end of the stream.
+    #[inline]
+    pub fn total(mut self, timeout: Duration) -> Self {
+        self.total = Some(timeout);
+        self
+    }
+}
+
+// the timers of a ByteStream.
+pub(crate) struct TimeoutState<E> {
+    timeouts: Timeouts,
+    // waiting for the inner stream, reset on every item.
+    inner: Option<Delay>,
+    total: Option<Delay>,
+    // when the last chunk was returned, until the next poll, and the
+    // timer that wakes the task once the client is late.
+    chunk_sent: Option<(Instant, Delay)>,
+    to_error: fn(StreamTimeout) -> E,
+}
+
+impl<E> TimeoutState<E> {
+    fn expired(&self, kind: TimeoutKind, after: Duration) -> E {
+        #[cfg(feature = "log")]
+        warn!("ByteStream {} after {:?}", kind, after);
+        (self.to_error)(StreamTimeout { kind, after })
+    }
+    // check the client gap and the total duration, on each poll, and
+    // register the total timer with the task.
+    pub(crate) fn check(&mut self, cx: &mut Context<'_>, stats: &StreamStats) -> Option<E> {
+        if let (Some((sent, _)), Some(gap)) = (self.chunk_sent.take(), self.timeouts.client_gap) {
+            if sent.elapsed() > gap {
+                return Some(self.expired(TimeoutKind::ClientGap, gap));
+            }
+        }
+        let total = self.timeouts.total?;
+        if stats.elapsed() > total {
+            return Some(self.expired(TimeoutKind::Total, total));
+        }
+        let delay = self
+            .total
+            .get_or_insert_with(|| Delay::new(total.saturating_sub(stats.elapsed())));
+        match Pin::new(delay).poll(cx) {
+            Poll::Ready(()) => Some(self.expired(TimeoutKind::Total, total)),
+            Poll::Pending => None,
+        }
+    }
+    // poll the timers while the inner stream is pending.
+    pub(crate) fn poll_pending(&mut self, cx: &mut Context<'_>, non_empty: bool) -> Option<E> {
+        let (kind, after) = if non_empty {
+            (TimeoutKind::Idle, self.timeouts.idle?)
+        } else {
+            (TimeoutKind::FirstRow, self.timeouts.first_row?)
+        };
+        let inner = self.inner.get_or_insert_with(|| Delay::new(after));
+        match Pin::new(inner).poll(cx) {
+            Poll::Ready(()) => Some(self.expired(kind, after)),
+            Poll::Pending => None,
+        }
+    }
+    #[inline]
+    pub(crate) fn item(&mut self) {
+        self.inner = None;
+    }
+    // start the client gap, registered with the task, once a chunk is
+    // returned.
+    pub(crate) fn chunk_sent(&mut self, cx: &mut Context<'_>) {
+        if let Some(gap) = self.timeouts.client_gap {
+            let mut delay = Delay::new(gap);
+            let _ = Pin::new(&mut delay).poll(cx);
+            self.chunk_sent = Some((Instant::now(), delay));
+        }
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// End the stream with a StreamTimeout error when a timeout
+    /// expires. The error is written by on_error(), if it is set. The
+    /// inner stream is dropped at once, releasing the query.
+    #[inline]
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self
+    where
+        OuterError: From<StreamTimeout>,
+    {
+        self.timeouts = Some(TimeoutState {
+            timeouts,
+            inner: None,
+            total: None,
+            chunk_sent: None,
+            to_error: OuterError::from,
+        });
+        self
+    }
+}
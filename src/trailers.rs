@@ -0,0 +1,98 @@
+use crate::{ByteStream, BytesWriter, StreamStats};
+use futures::TryStream;
+use std::fmt;
+
+/// The trailer name of the number of rows.
+pub const ROW_COUNT: &str = "X-Row-Count";
+/// The trailer name of the hex SHA-256 of the body.
+pub const CONTENT_SHA256: &str = "X-Content-SHA256";
+/// The trailer name of the stream status, complete or error.
+pub const STREAM_STATUS: &str = "X-Stream-Status";
+/// The trailer name of the token of the latest checkpoint.
+pub const RESUME_TOKEN: &str = "X-Resume-Token";
+
+/// Whether the stream sent the whole document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStatus {
+    Complete,
+    Error,
+}
+
+impl fmt::Display for StreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StreamStatus::Complete => "complete",
+            StreamStatus::Error => "error",
+        })
+    }
+}
+
+/// The values sent as HTTP trailers once a ByteStream ends, so that a
+/// client can verify that the body is complete and uncorrupted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpTrailers {
+    /// Number of rows written.
+    pub row_count: usize,
+    /// The hex SHA-256 of every emitted chunk.
+    pub sha256: String,
+    pub status: StreamStatus,
+    /// The token of the latest checkpoint, if checkpoints are recorded,
+    /// from which a failed stream can be resumed.
+    pub resume_token: Option<String>,
+}
+
+impl HttpTrailers {
+    /// The trailer names and values.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            (ROW_COUNT, self.row_count.to_string()),
+            (CONTENT_SHA256, self.sha256.clone()),
+            (STREAM_STATUS, self.status.to_string()),
+        ];
+        if let Some(token) = &self.resume_token {
+            headers.push((RESUME_TOKEN, token.clone()));
+        }
+        headers
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// For clients that do not support HTTP trailers, write the same
+    /// values at the end of the document, which becomes the value of
+    /// "data": `{"data":[...],"trailers":{"X-Row-Count":3,...}}`. It
+    /// wraps the prefix, suffix, envelope and trailer, in any order.
+    /// The checksum covers the body up to, but not including, the
+    /// trailers object. Since it is only written once the stream is
+    /// complete, a missing trailers object means the document is
+    /// truncated.
+    pub fn embed_trailers(mut self) -> Self {
+        self.embedded_trailers = true;
+        self.checksum()
+    }
+}
+
+// the start of a document having embedded trailers.
+pub(crate) const EMBEDDED_PREFIX: &[u8] = br#"{"data":"#;
+// written after the data, before the checksum is taken.
+pub(crate) const EMBEDDED_TRAILERS: &[u8] = br#","trailers":"#;
+
+// the trailers object, and the end of the document.
+pub(crate) fn embedded_trailers(stats: &StreamStats) -> String {
+    format!(
+        r#"{{"{}":{},"{}":"{}","{}":"{}"}}}}"#,
+        ROW_COUNT,
+        stats.item_count,
+        CONTENT_SHA256,
+        stats.sha256.as_deref().unwrap_or_default(),
+        STREAM_STATUS,
+        StreamStatus::Complete
+    )
+}
@@ -0,0 +1,512 @@
+use crate::{to_fields_with_skipped, ByteStream, BytesWriter, ColumnInfo};
+use bytes::Bytes;
+use crc32fast::Hasher;
+use flate2::{write::DeflateEncoder, Compression};
+use futures::{
+    task::{Context, Poll},
+    Stream, TryStream,
+};
+use pin_project::pin_project;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+    mem,
+    pin::Pin,
+};
+
+/// The Content-Type of an XLSX workbook.
+pub const XLSX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// A Content-Disposition header that saves the response as the given
+/// file name, with an ascii fallback for older clients, e.g.
+/// `attachment; filename="widgets.xlsx"; filename*=UTF-8''widgets.xlsx`.
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c == ' ' || c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::with_capacity(filename.len());
+    for b in filename.bytes() {
+        match b {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+// the most characters Excel allows in a cell.
+const XLSX_CELL_MAX_CHARS: usize = 32767;
+
+// write text, escaped for xml, without the characters xml disallows.
+fn write_escaped(buf: &mut BytesWriter, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => buf.0.extend_from_slice(b"&amp;"),
+            '<' => buf.0.extend_from_slice(b"&lt;"),
+            '>' => buf.0.extend_from_slice(b"&gt;"),
+            '"' => buf.0.extend_from_slice(b"&quot;"),
+            '\t' | '\n' | '\r' => buf.0.extend_from_slice(&[c as u8]),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => (),
+            c => buf
+                .0
+                .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+}
+
+fn write_text_cell(buf: &mut BytesWriter, text: &str) {
+    let text = match text.char_indices().nth(XLSX_CELL_MAX_CHARS) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    };
+    buf.0
+        .extend_from_slice(br#"<c t="inlineStr"><is><t xml:space="preserve">"#);
+    write_escaped(buf, text);
+    buf.0.extend_from_slice(b"</t></is></c>");
+}
+
+/// Write a json value as a worksheet cell: a number, a boolean, or
+/// text. Arrays and objects are written as their json text, and null
+/// as an empty cell.
+pub fn write_xlsx_cell(buf: &mut BytesWriter, value: &Value) -> Result<(), serde_json::Error> {
+    match value {
+        Value::Null => buf.0.extend_from_slice(b"<c/>"),
+        Value::Bool(b) => buf.0.extend_from_slice(if *b {
+            br#"<c t="b"><v>1</v></c>"#
+        } else {
+            br#"<c t="b"><v>0</v></c>"#
+        }),
+        Value::Number(n) => {
+            buf.0.extend_from_slice(b"<c><v>");
+            buf.0.extend_from_slice(n.to_string().as_bytes());
+            buf.0.extend_from_slice(b"</v></c>");
+        }
+        Value::String(s) => write_text_cell(buf, s),
+        Value::Array(_) | Value::Object(_) => write_text_cell(buf, &serde_json::to_string(value)?),
+    }
+    Ok(())
+}
+
+/// Write a struct or map as a worksheet row of its field values, in
+/// field order. The fields that a struct skips are written as empty
+/// cells. Use it as the serializer of ByteStream::xlsx().
+pub fn write_xlsx_row<T: Serialize + ?Sized>(
+    buf: &mut BytesWriter,
+    record: &T,
+) -> Result<(), serde_json::Error> {
+    buf.0.extend_from_slice(b"<row>");
+    for (_, value) in to_fields_with_skipped(record)? {
+        write_xlsx_cell(buf, &value)?;
+    }
+    buf.0.extend_from_slice(b"</row>");
+    Ok(())
+}
+
+// the header row of the column names.
+fn write_xlsx_header(buf: &mut BytesWriter, columns: &[ColumnInfo]) {
+    if columns.is_empty() {
+        return;
+    }
+    buf.0.extend_from_slice(b"<row>");
+    for column in columns {
+        write_text_cell(buf, &column.name);
+    }
+    buf.0.extend_from_slice(b"</row>");
+}
+
+const SHEET_PREFIX: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    "\n",
+    r#"<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#
+);
+const SHEET_SUFFIX: &str = "</sheetData></worksheet>";
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    OuterError: From<InnerError> + From<serde_json::Error> + From<io::Error> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Write an XLSX workbook of one worksheet, having a header row of
+    /// the columns given the first item, or None if the stream is
+    /// empty, e.g. from ColumnInfo::from_row() or from_describe(). The
+    /// serializer should write each item as a row, e.g. using
+    /// write_xlsx_row(). Do not set on_error(), which writes json.
+    pub fn xlsx<F>(self, mut columns: F) -> Xlsx<Self>
+    where
+        F: FnMut(Option<&<InnerStream as TryStream>::Ok>) -> Result<Vec<ColumnInfo>, OuterError>
+            + Send
+            + 'static,
+    {
+        Xlsx::new(
+            self.preamble(move |buf, first| {
+                write_xlsx_header(buf, &columns(first)?);
+                Ok(())
+            })
+            .prefix(SHEET_PREFIX)
+            .delimiter("")
+            .suffix(SHEET_SUFFIX),
+        )
+    }
+}
+
+impl<InnerStream, InnerError, Serializer, OuterError>
+    ByteStream<InnerStream, InnerError, Serializer, OuterError>
+where
+    InnerError: std::error::Error,
+    InnerStream: TryStream<Error = InnerError>,
+    <InnerStream as TryStream>::Ok: Serialize,
+    OuterError: From<InnerError> + From<serde_json::Error> + From<io::Error> + std::error::Error,
+    Serializer:
+        FnMut(&mut BytesWriter, &<InnerStream as TryStream>::Ok) -> Result<(), OuterError> + Unpin,
+{
+    /// Write an XLSX workbook whose header row is named after the
+    /// fields of the first record.
+    pub fn field_xlsx(self) -> Xlsx<Self> {
+        self.xlsx(|first| match first {
+            Some(record) => Ok(ColumnInfo::from_fields(record)?),
+            None => Ok(vec![]),
+        })
+    }
+}
+
+// a zip entry, for the central directory.
+struct ZipEntry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+}
+
+// the entry being deflated.
+struct Deflating {
+    name: String,
+    offset: u64,
+    encoder: DeflateEncoder<Vec<u8>>,
+    crc: Hasher,
+    size: u64,
+    compressed: u64,
+}
+
+// the version needed to extract, 2.0 for deflate.
+const ZIP_VERSION: u16 = 20;
+// the sizes and crc follow the data, in a data descriptor; utf-8 names.
+const ZIP_FLAGS: u16 = 0x0808;
+const ZIP_DEFLATE: u16 = 8;
+// 1980-01-01 00:00, the earliest dos date.
+const ZIP_TIME: u16 = 0;
+const ZIP_DATE: u16 = 0x21;
+
+// without zip64, sizes and offsets are limited to 4 GiB.
+fn zip32(n: u64) -> io::Result<u32> {
+    u32::try_from(n).map_err(|_| io::Error::other("xlsx larger than 4 GiB"))
+}
+
+// writes a zip archive sequentially, so that no entry needs to be
+// revisited once its data is written.
+struct ZipWriter {
+    out: Vec<u8>,
+    // the offset of out in the archive.
+    position: u64,
+    entries: Vec<ZipEntry>,
+    current: Option<Deflating>,
+}
+
+impl ZipWriter {
+    fn new() -> Self {
+        Self {
+            out: Vec::new(),
+            position: 0,
+            entries: Vec::new(),
+            current: None,
+        }
+    }
+    fn offset(&self) -> u64 {
+        self.position + self.out.len() as u64
+    }
+    fn u16(&mut self, n: u16) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+    fn u32(&mut self, n: u32) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+    // the local header, with a zero crc and sizes.
+    fn begin(&mut self, name: &str) {
+        let offset = self.offset();
+        self.u32(0x04034b50);
+        self.u16(ZIP_VERSION);
+        self.u16(ZIP_FLAGS);
+        self.u16(ZIP_DEFLATE);
+        self.u16(ZIP_TIME);
+        self.u16(ZIP_DATE);
+        self.u32(0);
+        self.u32(0);
+        self.u32(0);
+        self.u16(name.len() as u16);
+        self.u16(0);
+        self.out.extend_from_slice(name.as_bytes());
+        self.current = Some(Deflating {
+            name: name.to_string(),
+            offset,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+            crc: Hasher::new(),
+            size: 0,
+            compressed: 0,
+        });
+    }
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let current = self.current.as_mut().expect("no zip entry");
+        current.crc.update(data);
+        current.size += data.len() as u64;
+        current.encoder.write_all(data)?;
+        let deflated = current.encoder.get_mut();
+        current.compressed += deflated.len() as u64;
+        self.out.append(deflated);
+        Ok(())
+    }
+    // the rest of the data, and the data descriptor.
+    fn end(&mut self) -> io::Result<()> {
+        let current = self.current.take().expect("no zip entry");
+        let deflated = current.encoder.finish()?;
+        let entry = ZipEntry {
+            name: current.name,
+            offset: zip32(current.offset)?,
+            crc: current.crc.finalize(),
+            compressed: zip32(current.compressed + deflated.len() as u64)?,
+            size: zip32(current.size)?,
+        };
+        self.out.extend_from_slice(&deflated);
+        self.u32(0x08074b50);
+        self.u32(entry.crc);
+        self.u32(entry.compressed);
+        self.u32(entry.size);
+        self.entries.push(entry);
+        Ok(())
+    }
+    fn entry(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.begin(name);
+        self.write(data)?;
+        self.end()
+    }
+    // the central directory, and its end record.
+    fn finish(&mut self) -> io::Result<()> {
+        let start = self.offset();
+        let entries = mem::take(&mut self.entries);
+        for entry in &entries {
+            self.u32(0x02014b50);
+            self.u16(ZIP_VERSION);
+            self.u16(ZIP_VERSION);
+            self.u16(ZIP_FLAGS);
+            self.u16(ZIP_DEFLATE);
+            self.u16(ZIP_TIME);
+            self.u16(ZIP_DATE);
+            self.u32(entry.crc);
+            self.u32(entry.compressed);
+            self.u32(entry.size);
+            self.u16(entry.name.len() as u16);
+            // extra field, comment, disk, and attributes.
+            self.u16(0);
+            self.u16(0);
+            self.u16(0);
+            self.u16(0);
+            self.u32(0);
+            self.u32(entry.offset);
+            self.out.extend_from_slice(entry.name.as_bytes());
+        }
+        let count = entries.len() as u16;
+        let size = zip32(self.offset() - start)?;
+        let start = zip32(start)?;
+        self.u32(0x06054b50);
+        self.u16(0);
+        self.u16(0);
+        self.u16(count);
+        self.u16(count);
+        self.u32(size);
+        self.u32(start);
+        self.u16(0);
+        Ok(())
+    }
+    fn take(&mut self) -> Bytes {
+        self.position += self.out.len() as u64;
+        Bytes::from(mem::take(&mut self.out))
+    }
+}
+
+const CONTENT_TYPES: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    "\n",
+    r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">"#,
+    r#"<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>"#,
+    r#"<Default Extension="xml" ContentType="application/xml"/>"#,
+    r#"<Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>"#,
+    r#"<Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#,
+    "</Types>"
+);
+
+const ROOT_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    "\n",
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/>"#,
+    "</Relationships>"
+);
+
+const WORKBOOK_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    "\n",
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/>"#,
+    "</Relationships>"
+);
+
+fn workbook(sheet_name: &str) -> Vec<u8> {
+    let mut buf = BytesWriter(Default::default());
+    buf.0.extend_from_slice(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            "\n",
+            r#"<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" "#,
+            r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">"#,
+            r#"<sheets><sheet name=""#
+        )
+        .as_bytes(),
+    );
+    write_escaped(&mut buf, sheet_name);
+    buf.0
+        .extend_from_slice(br#"" sheetId="1" r:id="rId1"/></sheets></workbook>"#);
+    buf.0.to_vec()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XlsxState {
+    Start,
+    Sheet,
+    Done,
+}
+
+/// An XLSX workbook, zipped as it is streamed, having one worksheet
+/// whose xml is the output of the inner stream, e.g. of
+/// ByteStream::xlsx(). Each zip entry is followed by a data
+/// descriptor, so nothing is buffered beyond the current chunk. The
+/// workbook is limited to 4 GiB, since zip64 is not written.
+#[pin_project(project = XlsxProj)]
+pub struct Xlsx<S> {
+    #[pin]
+    inner_stream: S,
+    sheet_name: String,
+    zip: ZipWriter,
+    state: XlsxState,
+}
+
+impl<S> Xlsx<S> {
+    pub fn new(inner_stream: S) -> Self {
+        Self {
+            inner_stream,
+            sheet_name: "Sheet1".to_string(),
+            zip: ZipWriter::new(),
+            state: XlsxState::Start,
+        }
+    }
+    /// The name of the worksheet, "Sheet1" by default. Characters
+    /// Excel disallows are replaced, and it is truncated to 31.
+    pub fn sheet_name(mut self, name: &str) -> Self {
+        self.sheet_name = name
+            .chars()
+            .map(|c| match c {
+                '[' | ']' | ':' | '*' | '?' | '/' | '\\' => '_',
+                c => c,
+            })
+            .take(31)
+            .collect();
+        self
+    }
+}
+
+impl<S> XlsxProj<'_, S> {
+    // the parts other than the worksheet, and the worksheet's header.
+    fn start(&mut self) -> io::Result<()> {
+        self.zip
+            .entry("[Content_Types].xml", CONTENT_TYPES.as_bytes())?;
+        self.zip.entry("_rels/.rels", ROOT_RELS.as_bytes())?;
+        self.zip
+            .entry("xl/workbook.xml", &workbook(self.sheet_name))?;
+        self.zip
+            .entry("xl/_rels/workbook.xml.rels", WORKBOOK_RELS.as_bytes())?;
+        self.zip.begin("xl/worksheets/sheet1.xml");
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.zip.end()?;
+        self.zip.finish()
+    }
+}
+
+impl<S, E> Stream for Xlsx<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: From<io::Error>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let result = match *this.state {
+                XlsxState::Done => return Poll::Ready(None),
+                XlsxState::Start => {
+                    *this.state = XlsxState::Sheet;
+                    this.start()
+                }
+                XlsxState::Sheet => match this.inner_stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => this.zip.write(&chunk),
+                    Poll::Ready(Some(Err(e))) => {
+                        *this.state = XlsxState::Done;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    Poll::Ready(None) => {
+                        *this.state = XlsxState::Done;
+                        this.finish()
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+            if let Err(e) = result {
+                *this.state = XlsxState::Done;
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            // the deflater may hold back a small chunk.
+            if !this.zip.out.is_empty() {
+                return Poll::Ready(Some(Ok(this.zip.take())));
+            }
+        }
+    }
+}
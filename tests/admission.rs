@@ -0,0 +1,45 @@
+use futures::{executor::block_on, stream, FutureExt, StreamExt};
+use sqlx_actix_streaming::*;
+use std::time::Duration;
+
+#[test]
+fn admission_permits() {
+    let admission = Admission::new(2);
+    let a = block_on(admission.admit()).unwrap();
+    let _b = block_on(admission.admit()).unwrap();
+    assert_eq!(admission.stats().in_use, 2);
+    // the third waits for a permit to be released.
+    let mut c = admission.admit().boxed();
+    assert!((&mut c).now_or_never().is_none());
+    assert_eq!(admission.stats().waiting, 1);
+    drop(a);
+    let _c = c.now_or_never().unwrap().unwrap();
+    let stats = admission.stats();
+    assert_eq!((stats.admitted, stats.in_use, stats.waiting), (3, 2, 0));
+}
+
+#[test]
+fn admission_rejects_after_timeout() {
+    let admission = Admission::new(1).queue_timeout(Duration::from_millis(10));
+    let _a = block_on(admission.admit()).unwrap();
+    let e = block_on(admission.admit()).unwrap_err();
+    assert_eq!(e.retry_after, Duration::from_secs(1));
+    let stats = admission.stats();
+    assert_eq!((stats.admitted, stats.rejected, stats.waiting), (1, 1, 0));
+}
+
+#[test]
+fn admitted_stream_releases_permit() {
+    let admission = Admission::new(1);
+    let permit = block_on(admission.admit()).unwrap();
+    let mut s = Admitted::new(stream::iter(0..2), permit);
+    assert_eq!(block_on(s.next()), Some(0));
+    assert_eq!(admission.stats().in_use, 1);
+    assert_eq!(block_on(s.next()), Some(1));
+    assert_eq!(block_on(s.next()), None);
+    // released at the end, before the stream is dropped.
+    assert_eq!(admission.stats().in_use, 0);
+    let permit = block_on(admission.admit()).unwrap();
+    drop(Admitted::new(stream::iter(0..2), permit));
+    assert_eq!(admission.stats().in_use, 0);
+}
@@ -0,0 +1,84 @@
+use futures::{executor::block_on, stream, StreamExt};
+use serde_json::Value;
+use sqlx_actix_streaming::*;
+
+fn numbers(n: usize, pool: Option<&BufferPool>) -> Vec<bytes::Bytes> {
+    let s = ByteStream::with_size(
+        stream::iter((0..n).map(Ok::<_, Error>)),
+        |buf: &mut BytesWriter, n: &usize| Ok::<_, Error>(serde_json::to_writer(buf, n)?),
+        16,
+    );
+    let s = match pool {
+        Some(pool) => s.pool(pool.clone()),
+        None => s,
+    };
+    block_on(s.map(Result::unwrap).collect())
+}
+
+fn concat(chunks: &[bytes::Bytes]) -> Vec<u8> {
+    chunks.iter().flat_map(|chunk| chunk.to_vec()).collect()
+}
+
+#[test]
+fn same_output() {
+    let pool = BufferPool::new(8);
+    let pooled = numbers(100, Some(&pool));
+    assert!(pooled.len() > 1);
+    assert_eq!(concat(&pooled), concat(&numbers(100, None)));
+    let value: Value = serde_json::from_slice(&concat(&pooled)).unwrap();
+    assert_eq!(value.as_array().unwrap().len(), 100);
+}
+
+#[test]
+fn takes_buffers_when_written() {
+    let pool = BufferPool::new(8);
+    let s = ByteStream::with_size(
+        stream::iter((0..100).map(Ok::<_, Error>)),
+        |buf: &mut BytesWriter, n: &usize| Ok::<_, Error>(serde_json::to_writer(buf, n)?),
+        16,
+    )
+    .pool(pool.clone());
+    assert_eq!(pool.stats(), PoolStats::default());
+    let chunks: Vec<_> = block_on(s.map(Result::unwrap).collect());
+    let stats = pool.stats();
+    assert_eq!(stats.hits + stats.misses, chunks.len());
+}
+
+#[test]
+fn reuses_dropped_chunks() {
+    let pool = BufferPool::new(64);
+    let chunks = numbers(100, Some(&pool));
+    let first = pool.stats();
+    assert_eq!(first.hits, 0);
+    assert_eq!(first.returned, 0);
+    drop(chunks);
+    let returned = pool.stats().returned;
+    assert!(returned > 0);
+    let chunks = numbers(100, Some(&pool));
+    let second = pool.stats();
+    assert!(second.hits > 0);
+    assert_eq!(second.misses, first.misses);
+    drop(chunks);
+}
+
+#[test]
+fn bounded() {
+    let pool = BufferPool::new(2);
+    let chunks = numbers(100, Some(&pool));
+    let n = chunks.len();
+    drop(chunks);
+    let stats = pool.stats();
+    assert_eq!(stats.returned, 2);
+    assert_eq!(stats.discarded, n - 2);
+}
+
+#[test]
+fn get_rounds_up_to_class() {
+    let pool = BufferPool::new(1);
+    assert!(pool.get(1000).capacity() >= 1024);
+    let buf = pool.get(100);
+    let chunk = pool.freeze(buf);
+    drop(chunk);
+    assert!(pool.get(128).capacity() >= 128);
+    assert_eq!(pool.stats().hits, 1);
+}
@@ -0,0 +1,725 @@
+use futures::{
+    executor::block_on,
+    stream,
+    task::{noop_waker, Context, Poll},
+    Stream, StreamExt,
+};
+use serde::{ser, Serialize, Serializer};
+use serde_json::{json, Value};
+use sqlx_actix_streaming::*;
+use std::{collections::VecDeque, io, pin::Pin, time::Duration};
+
+#[derive(Serialize, Clone, Debug)]
+struct Widget {
+    id: i64,
+    name: String,
+}
+
+fn widgets(n: i64) -> Vec<Widget> {
+    (1..=n)
+        .map(|id| Widget {
+            id,
+            name: format!("widget {}", id),
+        })
+        .collect()
+}
+
+fn write_widget(buf: &mut BytesWriter, widget: &Widget) -> Result<(), Error> {
+    Ok(serde_json::to_writer(buf, widget)?)
+}
+
+// a stream that returns a scripted sequence of polls.
+struct Script<T>(VecDeque<Poll<Option<Result<T, io::Error>>>>);
+
+impl<T> Script<T> {
+    fn new(polls: Vec<Poll<Option<Result<T, io::Error>>>>) -> Self {
+        Self(polls.into())
+    }
+}
+
+impl<T: Unpin> Stream for Script<T> {
+    type Item = Result<T, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.0.pop_front().unwrap_or(Poll::Ready(None));
+        if poll.is_pending() {
+            cx.waker().wake_by_ref();
+        }
+        poll
+    }
+}
+
+fn ok_items<T>(items: Vec<T>) -> Script<T> {
+    Script::new(
+        items
+            .into_iter()
+            .map(|i| Poll::Ready(Some(Ok(i))))
+            .collect(),
+    )
+}
+
+// concatenate the chunks of a byte stream.
+fn collect<S, B, E>(s: S) -> Result<String, E>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    let mut out = Vec::new();
+    for chunk in block_on(s.collect::<Vec<_>>()) {
+        out.extend_from_slice(chunk?.as_ref());
+    }
+    Ok(String::from_utf8(out).unwrap())
+}
+
+fn collect_json<S, B, E>(s: S) -> Value
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Debug,
+{
+    let text = collect(s).unwrap();
+    serde_json::from_str(&text).unwrap_or_else(|e| panic!("invalid json: {}: {}", e, text))
+}
+
+#[test]
+fn empty() {
+    let s = ByteStream::new(ok_items(widgets(0)), write_widget);
+    assert_eq!(collect(s).unwrap(), "[]");
+}
+
+#[test]
+fn one() {
+    let s = ByteStream::new(ok_items(widgets(1)), write_widget);
+    assert_eq!(collect_json(s), json!([{"id": 1, "name": "widget 1"}]));
+}
+
+#[test]
+fn many() {
+    let s = ByteStream::with_size(ok_items(widgets(1000)), write_widget, 64);
+    let chunks = block_on(s.collect::<Vec<_>>());
+    assert!(chunks.len() > 1);
+    let text: Vec<u8> = chunks
+        .into_iter()
+        .flat_map(|chunk| chunk.unwrap().to_vec())
+        .collect();
+    let value: Value = serde_json::from_slice(&text).unwrap();
+    let rows = value.as_array().unwrap();
+    assert_eq!(rows.len(), 1000);
+    assert_eq!(rows[999], json!({"id": 1000, "name": "widget 1000"}));
+}
+
+#[test]
+fn custom_framing() {
+    let s = ByteStream::new(ok_items(widgets(3)), write_widget)
+        .prefix(r#"{"rows":["#)
+        .delimiter(",\n")
+        .suffix("]}");
+    let text = collect(s).unwrap();
+    assert_eq!(text.matches(",\n").count(), 2);
+    let value: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value["rows"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn envelope() {
+    let s = ByteStream::new(ok_items(widgets(3)), write_widget)
+        .envelope(&json!({"version": 1}), |stats, last: Option<&Widget>| {
+            json!({"count": stats.item_count, "next_cursor": last.map(|w| w.id)})
+        })
+        .unwrap();
+    assert_eq!(
+        collect_json(s),
+        json!({
+            "meta": {"version": 1},
+            "data": [
+                {"id": 1, "name": "widget 1"},
+                {"id": 2, "name": "widget 2"},
+                {"id": 3, "name": "widget 3"},
+            ],
+            "summary": {"count": 3, "next_cursor": 3},
+        })
+    );
+}
+
+#[test]
+fn envelope_empty() {
+    let s = ByteStream::new(ok_items(widgets(0)), write_widget)
+        .envelope(&(), |stats, last: Option<&Widget>| {
+            json!({"count": stats.item_count, "next_cursor": last.map(|w| w.id)})
+        })
+        .unwrap();
+    assert_eq!(
+        collect_json(s),
+        json!({"meta": null, "data": [], "summary": {"count": 0, "next_cursor": null}})
+    );
+}
+
+#[test]
+fn field_columns() {
+    let s = ByteStream::new(ok_items(widgets(2)), |buf: &mut BytesWriter, w: &Widget| {
+        Ok::<_, Error>(to_writer_positional(buf, &["id", "name"], w)?)
+    })
+    .field_columns();
+    let value = collect_json(s);
+    assert_eq!(value["cols"][0]["name"], "id");
+    assert_eq!(value["cols"][0]["json_type"], "number");
+    assert_eq!(value["cols"][1]["name"], "name");
+    assert_eq!(value["cols"][1]["json_type"], "string");
+    assert_eq!(value["rows"], json!([[1, "widget 1"], [2, "widget 2"]]));
+}
+
+#[test]
+fn field_columns_empty() {
+    let s = ByteStream::new(ok_items(widgets(0)), write_widget).field_columns();
+    assert_eq!(collect_json(s), json!({"cols": [], "rows": []}));
+}
+
+struct Unserializable;
+
+impl Serialize for Unserializable {
+    fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+        Err(ser::Error::custom("unserializable"))
+    }
+}
+
+#[test]
+fn serializer_error() {
+    let s = ByteStream::new(
+        ok_items(vec![Unserializable]),
+        |buf: &mut BytesWriter, item: &Unserializable| {
+            Ok::<_, Error>(serde_json::to_writer(buf, item)?)
+        },
+    );
+    match collect(s) {
+        Err(Error::Json(e)) => assert!(e.to_string().contains("unserializable")),
+        other => panic!("expected a json error: {:?}", other),
+    }
+}
+
+#[test]
+fn serializer_error_mid_item_in_document() {
+    // the second item fails after writing part of itself.
+    let s = ByteStream::new(ok_items(vec![1, 2, 3]), |buf: &mut BytesWriter, n: &i32| {
+        write!(buf, r#"{{"n":"#)?;
+        if *n == 2 {
+            return Err(Error::Io(io::Error::other("failed")));
+        }
+        write!(buf, "{}}}", n)?;
+        Ok(())
+    })
+    .embed_trailers()
+    .on_error(|buf, _| {
+        write!(buf, r#"],"error":"x"}}"#)?;
+        Ok(())
+    });
+    assert_eq!(collect_json(s), json!({"data": [{"n": 1}], "error": "x"}));
+}
+
+#[test]
+fn inner_error() {
+    let s = ByteStream::new(
+        Script::new(vec![
+            Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+            Poll::Ready(Some(Err(io::Error::other("failed")))),
+        ]),
+        write_widget,
+    );
+    match collect(s) {
+        Err(Error::Io(e)) => assert_eq!(e.to_string(), "failed"),
+        other => panic!("expected an io error: {:?}", other),
+    }
+}
+
+// poll a stream once, without a runtime.
+fn poll_once<S: Stream + Unpin>(s: &mut S) -> Poll<Option<S::Item>> {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    Pin::new(s).poll_next(&mut cx)
+}
+
+fn chunk<E: std::fmt::Debug>(poll: Poll<Option<Result<bytes::Bytes, E>>>) -> String {
+    match poll {
+        Poll::Ready(Some(Ok(chunk))) => String::from_utf8(chunk.to_vec()).unwrap(),
+        other => panic!("expected a chunk: {:?}", other),
+    }
+}
+
+#[test]
+fn pending_flushes_buffer() {
+    let mut w = widgets(2).into_iter();
+    let mut s = ByteStream::new(
+        Script::new(vec![
+            Poll::Pending,
+            Poll::Pending,
+            Poll::Ready(Some(Ok(w.next().unwrap()))),
+            Poll::Pending,
+            Poll::Ready(Some(Ok(w.next().unwrap()))),
+            Poll::Ready(None),
+        ]),
+        write_widget,
+    );
+    // the prefix is sent while the inner stream is pending.
+    assert_eq!(chunk(poll_once(&mut s)), "[");
+    // nothing is buffered, so the stream is pending.
+    assert!(poll_once(&mut s).is_pending());
+    assert_eq!(chunk(poll_once(&mut s)), r#"{"id":1,"name":"widget 1"}"#);
+    assert_eq!(chunk(poll_once(&mut s)), r#",{"id":2,"name":"widget 2"}]"#);
+    assert!(matches!(poll_once(&mut s), Poll::Ready(None)));
+    assert!(matches!(poll_once(&mut s), Poll::Ready(None)));
+}
+
+#[test]
+fn preamble_waits_for_first_item() {
+    let mut s = ByteStream::new(
+        Script::new(vec![
+            Poll::Pending,
+            Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+            Poll::Ready(None),
+        ]),
+        write_widget,
+    )
+    .field_columns();
+    // the deferred prefix is not sent while the inner stream is pending.
+    assert!(poll_once(&mut s).is_pending());
+    let text = chunk(poll_once(&mut s));
+    assert!(text.starts_with(r#"{"cols":[{"name":"id""#), "{}", text);
+}
+
+#[test]
+fn self_ref_stream() {
+    let s = SelfRefStream::build(widgets(3), |widgets| {
+        stream::iter(widgets.iter().cloned().map(Ok::<_, io::Error>)).boxed()
+    });
+    let s = ByteStream::new(s, write_widget);
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn unpin_not_required() {
+    // the async block makes the inner stream !Unpin.
+    let inner = stream::unfold(0, |n| async move {
+        (n < 3).then(|| (Ok::<_, io::Error>(widgets(n + 1).remove(n as usize)), n + 1))
+    });
+    let s = ByteStream::new(inner, write_widget);
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn unpin_not_required_by_adapters() {
+    let inner = || {
+        stream::unfold(0, |n| async move {
+            (n < 3).then(|| (Ok::<_, io::Error>(widgets(n + 1).remove(n as usize)), n + 1))
+        })
+    };
+    let cache = MemoryCache::new(1 << 20);
+    let s = ByteStream::new(inner(), write_widget).tee(cache.sink("widgets"));
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+    assert!(cache.get("widgets").is_some());
+    let s = ByteStream::new(inner(), write_widget).throttle(RateLimit::new(1 << 30, 1 << 30));
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+}
+
+// an inner stream borrowing the args, which it receives as &Args.
+#[allow(clippy::ptr_arg)]
+fn widget_rows(widgets: &Vec<Widget>) -> impl Stream<Item = Result<Widget, io::Error>> + '_ {
+    stream::iter(widgets.iter().cloned().map(Ok))
+}
+
+#[test]
+fn unboxed_self_ref_stream() {
+    let s = UnboxedSelfRefStream::build(widgets(3), widget_rows);
+    let s = ByteStream::new(s, write_widget);
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn unboxed_self_ref_stream_from_closure() {
+    // the stream owns its items, since a closure cannot return a borrow.
+    let s = UnboxedSelfRefStream::build(widgets(3), |widgets: &Vec<Widget>| {
+        stream::iter(widgets.clone().into_iter().map(Ok::<_, io::Error>))
+    });
+    assert_eq!(s.args().len(), 3);
+    let s = ByteStream::new(s, write_widget);
+    assert_eq!(collect_json(s).as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn self_ref_stream_args() {
+    let mut s = SelfRefStream::build(widgets(3), |widgets| {
+        stream::iter(widgets.iter().cloned().map(Ok::<_, io::Error>)).boxed()
+    });
+    assert_eq!(s.args().len(), 3);
+    assert!(block_on(s.next()).is_some());
+    assert_eq!(s.into_args()[2].id, 3);
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn http_trailers_complete() {
+    let (sender, mut receiver) = futures::channel::oneshot::channel();
+    let s = ByteStream::with_size(ok_items(widgets(100)), write_widget, 64).http_trailers(sender);
+    let body = collect(s).unwrap();
+    let trailers = receiver.try_recv().unwrap().unwrap();
+    assert_eq!(trailers.row_count, 100);
+    assert_eq!(trailers.sha256, sha256_hex(body.as_bytes()));
+    assert_eq!(trailers.status, StreamStatus::Complete);
+    assert_eq!(
+        trailers.headers()[2],
+        ("X-Stream-Status", "complete".to_string())
+    );
+}
+
+#[test]
+fn http_trailers_error() {
+    let (sender, mut receiver) = futures::channel::oneshot::channel();
+    let s = ByteStream::new(
+        Script::new(vec![
+            Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+            Poll::Ready(Some(Err(io::Error::other("failed")))),
+        ]),
+        write_widget,
+    )
+    .http_trailers(sender);
+    assert!(collect(s).is_err());
+    let trailers = receiver.try_recv().unwrap().unwrap();
+    assert_eq!(trailers.row_count, 1);
+    assert_eq!(trailers.status, StreamStatus::Error);
+}
+
+#[test]
+fn http_trailers_dropped() {
+    let (sender, mut receiver) = futures::channel::oneshot::channel();
+    let mut s = ByteStream::new(
+        Script::new(vec![
+            Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+            Poll::Pending,
+        ]),
+        write_widget,
+    )
+    .http_trailers(sender);
+    assert!(poll_once(&mut s).is_ready());
+    drop(s);
+    let trailers = receiver.try_recv().unwrap().unwrap();
+    assert_eq!(trailers.status, StreamStatus::Error);
+}
+
+#[test]
+fn embed_trailers() {
+    let s = ByteStream::with_size(ok_items(widgets(10)), write_widget, 64).embed_trailers();
+    let body = collect(s).unwrap();
+    let split = body.rfind(r#"{"X-Row-Count""#).unwrap();
+    let doc: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(doc["data"].as_array().unwrap().len(), 10);
+    assert_eq!(
+        doc["trailers"],
+        json!({
+            "X-Row-Count": 10,
+            "X-Content-SHA256": sha256_hex(&body.as_bytes()[..split]),
+            "X-Stream-Status": "complete",
+        })
+    );
+}
+
+#[test]
+fn embed_trailers_wraps_envelope() {
+    // embed_trailers() may come before the settings it wraps.
+    let s = ByteStream::with_size(ok_items(widgets(10)), write_widget, 64)
+        .embed_trailers()
+        .envelope(
+            &json!({"version": 1}),
+            |stats, _: Option<&Widget>| json!({ "count": stats.item_count }),
+        )
+        .unwrap();
+    let body = collect(s).unwrap();
+    let split = body.rfind(r#"{"X-Row-Count""#).unwrap();
+    let doc: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(doc["data"]["meta"]["version"], 1);
+    assert_eq!(doc["data"]["data"].as_array().unwrap().len(), 10);
+    assert_eq!(doc["data"]["summary"]["count"], 10);
+    assert_eq!(doc["trailers"]["X-Row-Count"], 10);
+    assert_eq!(
+        doc["trailers"]["X-Content-SHA256"],
+        sha256_hex(&body.as_bytes()[..split])
+    );
+}
+
+#[test]
+fn sections() {
+    let s = Sections::new()
+        .section(
+            "totals",
+            ByteStream::new(ok_items(vec![widgets(3).len()]), |buf, n: &usize| {
+                Ok::<_, Error>(write!(buf, r#"{{"count":{}}}"#, n)?)
+            })
+            .prefix("")
+            .suffix(""),
+        )
+        .section("top", ByteStream::new(ok_items(widgets(2)), write_widget))
+        .section(
+            "series",
+            ByteStream::new(ok_items(widgets(2)), |buf: &mut BytesWriter, w: &Widget| {
+                Ok::<_, Error>(to_writer_positional(buf, &["id", "name"], w)?)
+            }),
+        );
+    assert_eq!(
+        collect(s).unwrap(),
+        r#"{"totals":{"count":3},"top":[{"id":1,"name":"widget 1"},{"id":2,"name":"widget 2"}],"series":[[1,"widget 1"],[2,"widget 2"]]}"#
+    );
+    assert_eq!(collect(Sections::<Error>::new()).unwrap(), "{}");
+}
+
+#[test]
+fn sections_concurrency() {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    for concurrency in 1..=2 {
+        let polled = Arc::new(AtomicBool::new(false));
+        let second = {
+            let polled = polled.clone();
+            let mut items = widgets(2).into_iter();
+            stream::poll_fn(move |_| {
+                polled.store(true, Ordering::SeqCst);
+                Poll::Ready(items.next().map(Ok::<_, io::Error>))
+            })
+        };
+        let mut s = Sections::new()
+            .section(
+                "first",
+                ByteStream::new(
+                    Script::new(vec![
+                        Poll::Pending,
+                        Poll::Pending,
+                        Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+                        Poll::Ready(None),
+                    ]),
+                    write_widget,
+                ),
+            )
+            .section("second", ByteStream::new(second, write_widget))
+            .concurrency(concurrency);
+        // while the first section is pending, the second runs only if
+        // the concurrency allows it.
+        assert_eq!(chunk(poll_once(&mut s)), r#"{"first":["#);
+        assert!(poll_once(&mut s).is_pending());
+        assert_eq!(polled.load(Ordering::SeqCst), concurrency > 1);
+        let doc = format!(r#"{{"first":[{}"#, collect(s).unwrap());
+        let doc: Value = serde_json::from_str(&doc).unwrap();
+        assert_eq!(doc["first"].as_array().unwrap().len(), 1);
+        assert_eq!(doc["second"].as_array().unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn sections_error_in_turn() {
+    let failing = stream::iter(vec![Err(io::Error::other("failed"))]);
+    let mut s = Sections::new()
+        .section(
+            "first",
+            ByteStream::new(
+                Script::new(vec![
+                    Poll::Pending,
+                    Poll::Pending,
+                    Poll::Ready(Some(Ok(widgets(1).remove(0)))),
+                    Poll::Ready(None),
+                ]),
+                write_widget,
+            ),
+        )
+        .section("second", ByteStream::new(failing, write_widget))
+        .concurrency(2);
+    // the second section fails while the first is pending.
+    assert_eq!(chunk(poll_once(&mut s)), r#"{"first":["#);
+    assert!(poll_once(&mut s).is_pending());
+    assert_eq!(chunk(poll_once(&mut s)), r#"{"id":1,"name":"widget 1"}]"#);
+    assert!(matches!(poll_once(&mut s), Poll::Ready(Some(Err(_)))));
+    assert!(matches!(poll_once(&mut s), Poll::Ready(None)));
+}
+
+#[test]
+fn sections_buffer_size() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    let polls = Arc::new(AtomicUsize::new(0));
+    let second = {
+        let polls = polls.clone();
+        let mut items = widgets(100).into_iter();
+        stream::poll_fn(move |_| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(items.next().map(Ok::<_, io::Error>))
+        })
+    };
+    let mut s = Sections::new()
+        .section(
+            "first",
+            ByteStream::new(
+                Script::new(vec![Poll::Pending, Poll::Pending, Poll::Ready(None)]),
+                write_widget,
+            ),
+        )
+        .section("second", ByteStream::with_size(second, write_widget, 64))
+        .concurrency(2)
+        .buffer_size(100);
+    // while the first section is pending, the second stops once it has
+    // buffered 100 bytes, a few of its 100 rows.
+    assert_eq!(chunk(poll_once(&mut s)), r#"{"first":["#);
+    assert!(poll_once(&mut s).is_pending());
+    assert!(polls.load(Ordering::SeqCst) < 10);
+    let doc = format!(r#"{{"first":[{}"#, collect(s).unwrap());
+    let doc: Value = serde_json::from_str(&doc).unwrap();
+    assert_eq!(doc["second"].as_array().unwrap().len(), 100);
+}
+
+// rows, then a query that stalls.
+fn stalled(n: i64) -> impl Stream<Item = Result<Widget, io::Error>> + Unpin {
+    stream::iter(widgets(n).into_iter().map(Ok)).chain(stream::pending())
+}
+
+fn timeout_kind<T: std::fmt::Debug>(result: Result<T, Error>) -> TimeoutKind {
+    match result {
+        Err(Error::Timeout(e)) => e.kind,
+        other => panic!("expected a timeout: {:?}", other),
+    }
+}
+
+#[test]
+fn first_row_timeout() {
+    let s = ByteStream::new(stalled(0), write_widget)
+        .timeouts(Timeouts::new().first_row(Duration::from_millis(10)));
+    assert_eq!(timeout_kind(collect(s)), TimeoutKind::FirstRow);
+}
+
+#[test]
+fn total_timeout() {
+    let s = ByteStream::new(stalled(2), write_widget).timeouts(
+        Timeouts::new()
+            .idle(Duration::from_secs(60))
+            .total(Duration::from_millis(10)),
+    );
+    assert_eq!(timeout_kind(collect(s)), TimeoutKind::Total);
+}
+
+#[test]
+fn idle_timeout_in_document() {
+    let s = ByteStream::new(stalled(2), write_widget)
+        .timeouts(Timeouts::new().idle(Duration::from_millis(10)))
+        .on_error(|buf, e| {
+            let code = match e {
+                Error::Timeout(e) => e.kind.as_str(),
+                _ => "error",
+            };
+            write!(buf, r#",{{"error":"{}"}}]"#, code)?;
+            Ok(())
+        });
+    assert_eq!(
+        collect_json(s),
+        json!([
+            {"id": 1, "name": "widget 1"},
+            {"id": 2, "name": "widget 2"},
+            {"error": "idle_timeout"}
+        ])
+    );
+}
+
+#[test]
+fn first_row_timeout_in_columns() {
+    // the deferred prefix and columns are written before the error.
+    let s = ByteStream::new(stalled(0), write_widget)
+        .field_columns()
+        .timeouts(Timeouts::new().first_row(Duration::from_millis(10)))
+        .on_error(|buf, _| {
+            write!(buf, r#"],"error":"timeout"}}"#)?;
+            Ok(())
+        });
+    assert_eq!(
+        collect_json(s),
+        json!({"cols": [], "rows": [], "error": "timeout"})
+    );
+}
+
+#[test]
+fn client_gap_timeout() {
+    let mut s = ByteStream::with_size(ok_items(widgets(3)), write_widget, 16)
+        .timeouts(Timeouts::new().client_gap(Duration::from_millis(10)));
+    chunk(poll_once(&mut s));
+    std::thread::sleep(Duration::from_millis(20));
+    match poll_once(&mut s) {
+        Poll::Ready(Some(e)) => assert_eq!(timeout_kind(e), TimeoutKind::ClientGap),
+        other => panic!("expected a timeout: {:?}", other),
+    }
+    assert!(matches!(poll_once(&mut s), Poll::Ready(None)));
+}
+
+// a reader that reads one chunk and stops, until the task is woken.
+fn timeout_when_reader_stops(timeouts: Timeouts) -> TimeoutKind {
+    use futures::task::{waker, ArcWake};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    struct Woken(AtomicBool);
+    impl ArcWake for Woken {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    // sets the flag when the query is dropped.
+    struct Query(Arc<AtomicBool>);
+    impl Drop for Query {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    let dropped = Arc::new(AtomicBool::new(false));
+    let inner = {
+        let query = Query(dropped.clone());
+        let mut items = widgets(100).into_iter();
+        stream::poll_fn(move |_| {
+            let _ = &query;
+            Poll::Ready(items.next().map(Ok::<_, io::Error>))
+        })
+    };
+    let mut s = ByteStream::with_size(inner, write_widget, 16).timeouts(timeouts);
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = waker(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    chunk(Pin::new(&mut s).poll_next(&mut cx));
+    // the timer wakes the task, though the reader has not asked.
+    for _ in 0..100 {
+        if woken.0.load(Ordering::SeqCst) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(woken.0.load(Ordering::SeqCst));
+    // the query is dropped as the stream ends, not with the stream.
+    let kind = match Pin::new(&mut s).poll_next(&mut cx) {
+        Poll::Ready(Some(e)) => timeout_kind(e),
+        other => panic!("expected a timeout: {:?}", other),
+    };
+    assert!(dropped.load(Ordering::SeqCst));
+    assert!(matches!(poll_once(&mut s), Poll::Ready(None)));
+    kind
+}
+
+#[test]
+fn client_gap_timeout_when_reader_stops() {
+    let timeouts = Timeouts::new().client_gap(Duration::from_millis(10));
+    assert_eq!(timeout_when_reader_stops(timeouts), TimeoutKind::ClientGap);
+}
+
+#[test]
+fn total_timeout_when_reader_stops() {
+    let timeouts = Timeouts::new().total(Duration::from_millis(10));
+    assert_eq!(timeout_when_reader_stops(timeouts), TimeoutKind::Total);
+}
@@ -0,0 +1,60 @@
+use futures::{stream, FutureExt, StreamExt};
+use sqlx_actix_streaming::*;
+
+#[test]
+fn credit_limits_items() {
+    let credit = Credit::new();
+    let mut s = CreditStream::new(stream::iter(0..5), credit.clone());
+    assert_eq!(s.next().now_or_never(), None);
+    credit.grant(2);
+    assert_eq!(s.next().now_or_never(), Some(Some(0)));
+    assert_eq!(s.next().now_or_never(), Some(Some(1)));
+    assert_eq!(s.next().now_or_never(), None);
+    assert_eq!(credit.available(), 0);
+    credit.grant(10);
+    let rest: Vec<_> = s.collect().now_or_never().unwrap();
+    assert_eq!(rest, vec![2, 3, 4]);
+    assert_eq!(credit.available(), 7);
+}
+
+#[test]
+fn credit_cancel() {
+    let credit = Credit::new();
+    let mut s = CreditStream::new(stream::iter(0..5), credit.clone());
+    credit.grant(1);
+    assert_eq!(s.next().now_or_never(), Some(Some(0)));
+    credit.cancel();
+    assert_eq!(s.next().now_or_never(), Some(None));
+}
+
+#[test]
+fn open_queries_limit() {
+    let queries = OpenQueries::new(2);
+    let mut a = queries.open("a", stream::iter(0..3)).unwrap();
+    let b = queries.open("b", stream::iter(0..3)).unwrap();
+    assert_eq!(
+        queries.open("c", stream::iter(0..3)).err(),
+        Some(TooManyQueries(2))
+    );
+    assert!(queries.grant(&"a", 1));
+    assert!(!queries.grant(&"c", 1));
+    assert_eq!(a.next().now_or_never(), Some(Some(0)));
+    drop(b);
+    assert_eq!(queries.len(), 1);
+    let mut c = queries.open("c", stream::iter(0..3)).unwrap();
+    assert!(queries.cancel(&"c"));
+    assert_eq!(c.next().now_or_never(), Some(None));
+    queries.cancel_all();
+    assert!(queries.is_empty());
+    assert_eq!(a.next().now_or_never(), Some(None));
+}
+
+#[test]
+fn open_queries_replace() {
+    let queries = OpenQueries::new(1);
+    let mut old = queries.open(1, stream::iter(0..3)).unwrap();
+    let _new = queries.open(1, stream::iter(0..3)).unwrap();
+    assert_eq!(old.next().now_or_never(), Some(None));
+    drop(old);
+    assert_eq!(queries.len(), 1);
+}
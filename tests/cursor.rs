@@ -0,0 +1,114 @@
+// These run against the postgres server at POSTGRES_DATABASE_URL, so
+// they are ignored unless asked for, e.g.
+// `cargo test --features postgres --test cursor -- --ignored`.
+#![cfg(feature = "postgres")]
+
+use futures::{StreamExt, TryStreamExt};
+use sqlx::{postgres::PgPoolOptions, FromRow, PgPool};
+use sqlx_actix_streaming::*;
+
+#[derive(FromRow, Debug, PartialEq)]
+struct Id {
+    id: i32,
+}
+
+// a pool of one connection, so that a rollback queued by a dropped
+// transaction runs before the next query. The query logs each row it
+// produces into the table, so that a commit can be told from a
+// rollback.
+async fn pool(table: &str) -> PgPool {
+    let url = std::env::var("POSTGRES_DATABASE_URL").expect("POSTGRES_DATABASE_URL");
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await
+        .unwrap();
+    for sql in [
+        format!("DROP TABLE IF EXISTS {}", table),
+        format!("CREATE TABLE {} (id int)", table),
+        format!(
+            "CREATE OR REPLACE FUNCTION {0}_row(i int) RETURNS int AS \
+             'INSERT INTO {0} VALUES (i); SELECT i' LANGUAGE sql",
+            table
+        ),
+    ] {
+        sqlx::query(&sql).execute(&pool).await.unwrap();
+    }
+    pool
+}
+
+async fn logged(pool: &PgPool, table: &str) -> i64 {
+    sqlx::query_scalar(&format!("SELECT count(*) FROM {}", table))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs a postgres server at POSTGRES_DATABASE_URL"]
+async fn cursor_commits_when_complete() {
+    let table = "cursor_complete";
+    let pool = pool(table).await;
+    let rows: Vec<Id> = PgCursor::new(format!(
+        "SELECT {}_row(i) AS id FROM generate_series(1, $1) i",
+        table
+    ))
+    .bind(10)
+    .batch_size(3)
+    .fetch(&pool)
+    .try_collect()
+    .await
+    .unwrap();
+    assert_eq!(rows, (1..=10).map(|id| Id { id }).collect::<Vec<_>>());
+    assert_eq!(logged(&pool, table).await, 10);
+    // the cursor was closed, so it can be declared again.
+    let rows: Vec<Id> = PgCursor::new("SELECT 1 AS id")
+        .fetch(&pool)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "needs a postgres server at POSTGRES_DATABASE_URL"]
+async fn cursor_rolls_back_when_dropped() {
+    let table = "cursor_dropped";
+    let pool = pool(table).await;
+    let mut rows = PgCursor::new(format!(
+        "SELECT {}_row(i) AS id FROM generate_series(1, 10) i",
+        table
+    ))
+    .batch_size(3)
+    .fetch::<Id>(&pool);
+    assert_eq!(rows.next().await.unwrap().unwrap(), Id { id: 1 });
+    drop(rows);
+    assert_eq!(logged(&pool, table).await, 0);
+}
+
+#[tokio::test]
+#[ignore = "needs a postgres server at POSTGRES_DATABASE_URL"]
+async fn cursor_error_during_fetch() {
+    let table = "cursor_error";
+    let pool = pool(table).await;
+    // the fifth row divides by zero, in the third batch.
+    let results: Vec<Result<Id, sqlx::Error>> = PgCursor::new(format!(
+        "SELECT 10 / (5 - {}_row(i)) AS id FROM generate_series(1, 10) i",
+        table
+    ))
+    .batch_size(2)
+    .fetch(&pool)
+    .collect()
+    .await;
+    assert_eq!(results.len(), 5);
+    let ids: Vec<i32> = results[..4]
+        .iter()
+        .map(|row| row.as_ref().unwrap().id)
+        .collect();
+    assert_eq!(ids, vec![2, 3, 5, 10]);
+    match &results[4] {
+        Err(sqlx::Error::Database(e)) => assert_eq!(e.code().as_deref(), Some("22012")),
+        other => panic!("expected division by zero: {:?}", other),
+    }
+    assert_eq!(logged(&pool, table).await, 0);
+}
@@ -0,0 +1,60 @@
+use futures::{executor::block_on, stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sqlx_actix_streaming::*;
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct Widget {
+    id: i64,
+}
+
+fn sse(events: Vec<LiveEvent<Widget>>) -> String {
+    let s = ByteStream::new(
+        stream::iter(events.into_iter().map(Ok::<_, Error>)),
+        |buf: &mut BytesWriter, event: &LiveEvent<Widget>| event.write_sse::<Error>(buf),
+    )
+    .sse();
+    let body: Vec<u8> = block_on(s.map_ok(|chunk| chunk.to_vec()).try_concat()).unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+#[test]
+fn sse_events() {
+    let body = sse(vec![
+        LiveEvent::Snapshot(Widget { id: 1 }),
+        LiveEvent::Heartbeat,
+        LiveEvent::Change(Widget { id: 2 }),
+    ]);
+    assert_eq!(
+        body,
+        "event: snapshot\ndata: {\"id\":1}\n\n: heartbeat\n\nevent: change\ndata: {\"id\":2}\n\n"
+    );
+}
+
+#[test]
+fn sse_empty() {
+    assert_eq!(sse(vec![]), "");
+}
+
+#[test]
+fn heartbeat_when_idle() {
+    let idle = stream::pending::<Result<LiveEvent<Widget>, Error>>();
+    let events: Vec<_> = block_on(
+        HeartbeatStream::new(idle, Duration::from_millis(5))
+            .take(2)
+            .collect(),
+    );
+    assert!(events
+        .into_iter()
+        .all(|event| matches!(event, Ok(LiveEvent::Heartbeat))));
+}
+
+#[test]
+fn heartbeat_ends_with_inner_stream() {
+    let events = stream::iter(vec![Ok::<_, Error>(LiveEvent::Change(Widget { id: 1 }))]);
+    let events: Vec<_> =
+        block_on(HeartbeatStream::new(events, Duration::from_secs(60)).try_collect::<Vec<_>>())
+            .unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], LiveEvent::Change(Widget { id: 1 })));
+}
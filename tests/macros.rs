@@ -0,0 +1,336 @@
+// Each macro arm is expanded against sqlite. The query macros are
+// checked at compile time against DATABASE_URL in .cargo/config.toml, an empty
+// in-memory database, so the rows come from a common table expression.
+//
+// query_json!() is not covered: the records of sqlx::query!() do not
+// implement Serialize.
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{sqlite::*, Row};
+use sqlx_actix_streaming::*;
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+struct Widget {
+    id: i64,
+    serial: i64,
+    name: String,
+    description: String,
+}
+
+struct Params {
+    limit: i64,
+}
+
+const WIDGETS: &str = "WITH widgets(id, serial, name, description) AS (VALUES \
+    (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+    (2, 39822, 'flexarm', 'red flexible support arm'), \
+    (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+    SELECT * FROM widgets";
+
+// a stand-in for actix_web::HttpResponse, which returns the body stream.
+struct HttpResponse;
+
+struct Response<S> {
+    content_type: String,
+    body: S,
+}
+
+#[allow(non_snake_case)]
+impl HttpResponse {
+    fn Ok() -> Self {
+        HttpResponse
+    }
+}
+
+struct HttpResponseBuilder(String);
+
+impl HttpResponse {
+    fn content_type(self, content_type: &str) -> HttpResponseBuilder {
+        HttpResponseBuilder(content_type.to_string())
+    }
+}
+
+impl HttpResponseBuilder {
+    fn streaming<S>(self, body: S) -> Response<S> {
+        Response {
+            content_type: self.0,
+            body,
+        }
+    }
+}
+
+async fn pool() -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+async fn collect_json<S, B, E>(s: S) -> Value
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Debug,
+{
+    let mut out = Vec::new();
+    for chunk in s.collect::<Vec<_>>().await {
+        out.extend_from_slice(chunk.unwrap().as_ref());
+    }
+    serde_json::from_slice(&out)
+        .unwrap_or_else(|e| panic!("invalid json: {}: {}", e, String::from_utf8_lossy(&out)))
+}
+
+async fn response_json<S, B, E>(response: Response<S>) -> Value
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Debug,
+{
+    assert_eq!(response.content_type, "application/json");
+    collect_json(response.body).await
+}
+
+fn ids(value: &Value) -> Vec<i64> {
+    value
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|w| w["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn json_response() {
+    let pool = pool().await;
+    let params = Params { limit: 2 };
+    let response = json_response!(
+        pool,
+        params,
+        sqlx::query_as!(
+            Widget,
+            "WITH widgets(id, serial, name, description) AS (VALUES \
+             (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+             (2, 39822, 'flexarm', 'red flexible support arm'), \
+             (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+             SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+             FROM widgets LIMIT ?",
+            params.limit
+        )
+    );
+    let value = response_json(response).await;
+    assert_eq!(ids(&value), vec![1, 2]);
+    assert_eq!(value[0]["name"], "spanner");
+}
+
+#[tokio::test]
+async fn byte_stream() {
+    let pool = pool().await;
+    let params = Params { limit: 1 };
+    let s = byte_stream!(
+        pool,
+        params,
+        sqlx::query_as!(
+            Widget,
+            "WITH widgets(id, serial, name, description) AS (VALUES \
+             (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+             (2, 39822, 'flexarm', 'red flexible support arm'), \
+             (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+             SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+             FROM widgets LIMIT ?",
+            params.limit
+        )
+    );
+    assert_eq!(ids(&collect_json(s).await), vec![1]);
+}
+
+#[tokio::test]
+async fn json_response_alt_literal_args() {
+    let pool = pool().await;
+    let response = json_response_alt!(
+        Widget,
+        pool,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+         FROM widgets WHERE id > ?",
+        1
+    );
+    assert_eq!(ids(&response_json(response).await), vec![2, 3]);
+}
+
+#[tokio::test]
+async fn json_response_alt_params() {
+    let pool = pool().await;
+    let params = Params { limit: 3 };
+    let response = json_response_alt!(
+        Widget,
+        pool,
+        params,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+         FROM widgets LIMIT ?",
+        params.limit
+    );
+    assert_eq!(ids(&response_json(response).await), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn json_response_alt_sql_expr() {
+    let pool = pool().await;
+    let sql = format!("{} WHERE id < ?", WIDGETS);
+    let response = json_response_alt!(Widget, pool, sql, 3);
+    assert_eq!(ids(&response_json(response).await), vec![1, 2]);
+}
+
+#[tokio::test]
+async fn json_stream_literal_args() {
+    let pool = pool().await;
+    let s = json_stream!(
+        Widget,
+        pool,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+         FROM widgets WHERE id = ?",
+        3
+    );
+    assert_eq!(ids(&collect_json(s).await), vec![3]);
+}
+
+#[tokio::test]
+async fn json_stream_sql_expr() {
+    let pool = pool().await;
+    let s = json_stream!(Widget, pool, format!("{} WHERE id <> ?", WIDGETS), 2);
+    assert_eq!(ids(&collect_json(s).await), vec![1, 3]);
+}
+
+#[tokio::test]
+async fn query_stream_literal_sql() {
+    let pool = pool().await;
+    let rows: Vec<SqliteRow> = query_stream!(
+        pool,
+        "SELECT ? AS id UNION ALL SELECT ? AS id",
+        1_i64,
+        2_i64
+    )
+    .try_collect()
+    .await
+    .unwrap();
+    let ids: Vec<i64> = rows.iter().map(|row| row.get("id")).collect();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn query_stream_sql_expr() {
+    let pool = pool().await;
+    let rows: Vec<SqliteRow> = query_stream!(pool, format!("{} WHERE id >= ?", WIDGETS), 2)
+        .try_collect()
+        .await
+        .unwrap();
+    let names: Vec<String> = rows.iter().map(|row| row.get("name")).collect();
+    assert_eq!(names, vec!["flexarm", "bearing"]);
+}
+
+#[tokio::test]
+async fn query_as_stream_literal_args() {
+    let pool = pool().await;
+    let widgets: Vec<Widget> = query_as_stream!(
+        Widget,
+        pool,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+         FROM widgets WHERE serial > ?",
+        20000
+    )
+    .try_collect()
+    .await
+    .unwrap();
+    assert_eq!(widgets.len(), 2);
+}
+
+#[tokio::test]
+async fn query_as_stream_sql_expr() {
+    let pool = pool().await;
+    let widgets: Vec<Widget> = query_as_stream!(Widget, pool, WIDGETS.to_string(),)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(widgets.len(), 3);
+}
+
+fn write_widget(buf: &mut BytesWriter, widget: &Widget) -> Result<(), Error> {
+    Ok(serde_json::to_writer(buf, widget)?)
+}
+
+#[tokio::test]
+async fn query_as_byte_stream_literal_args() {
+    let pool = pool().await;
+    let s = query_as_byte_stream!(
+        Widget,
+        pool,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id AS \"id: i64\", serial AS \"serial: i64\", name, description \
+         FROM widgets WHERE id <> ?",
+        write_widget,
+        1
+    );
+    assert_eq!(ids(&collect_json(s).await), vec![2, 3]);
+}
+
+#[tokio::test]
+async fn query_as_byte_stream_sql_expr() {
+    let pool = pool().await;
+    let s = query_as_byte_stream!(Widget, pool, WIDGETS.to_string(), write_widget,);
+    assert_eq!(ids(&collect_json(s).await), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn query_byte_stream_literal_args() {
+    let pool = pool().await;
+    let s = query_byte_stream!(
+        pool,
+        "WITH widgets(id, serial, name, description) AS (VALUES \
+         (1, 10138, 'spanner', 'blue 10 guage joint spanner'), \
+         (2, 39822, 'flexarm', 'red flexible support arm'), \
+         (3, 52839, 'bearing', 'steel bearing for articulating joints')) \
+         SELECT id, name FROM widgets WHERE id > ?",
+        |buf: &mut BytesWriter, rec| {
+            Ok::<_, Error>(serde_json::to_writer(
+                buf,
+                &json!({"id": rec.id, "name": rec.name}),
+            )?)
+        },
+        2
+    );
+    assert_eq!(collect_json(s).await, json!([{"id": 3, "name": "bearing"}]));
+}
+
+#[tokio::test]
+async fn query_byte_stream_sql_expr() {
+    let pool = pool().await;
+    let s = query_byte_stream!(
+        pool,
+        format!("{} WHERE id < ?", WIDGETS),
+        |buf: &mut BytesWriter, row: &SqliteRow| {
+            let id: i64 = row.try_get("id")?;
+            Ok::<_, Error>(serde_json::to_writer(buf, &json!({ "id": id }))?)
+        },
+        2
+    );
+    assert_eq!(collect_json(s).await, json!([{"id": 1}]));
+}
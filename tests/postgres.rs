@@ -0,0 +1,68 @@
+// These run against the postgres server at POSTGRES_DATABASE_URL, so
+// they are ignored unless asked for, e.g.
+// `cargo test --features postgres --test postgres -- --ignored`.
+#![cfg(feature = "postgres")]
+
+use sqlx::PgPool;
+use sqlx_actix_streaming::*;
+
+async fn pool() -> PgPool {
+    let url = std::env::var("POSTGRES_DATABASE_URL").expect("POSTGRES_DATABASE_URL");
+    PgPool::connect(&url).await.unwrap()
+}
+
+async fn projected(pool: &PgPool, fields: &[&str], sql: &str) -> String {
+    let allowlist = fields
+        .iter()
+        .fold(FieldAllowlist::new(), |allowlist, field| {
+            allowlist.field(field)
+        });
+    let row = sqlx::query(sql).fetch_one(pool).await.unwrap();
+    let mut out = Vec::new();
+    allowlist
+        .project(None)
+        .unwrap()
+        .row_to_writer(&mut out, &row)
+        .unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs a postgres server at POSTGRES_DATABASE_URL"]
+async fn projection_of_postgres_types() {
+    let pool = pool().await;
+    let fields = [
+        "b", "i2", "i4", "i8", "f4", "f8", "n", "t", "u", "j", "jb", "d", "tm", "ts", "tz",
+        "missing",
+    ];
+    assert_eq!(
+        projected(
+            &pool,
+            &fields,
+            "SELECT true AS b, 2::int2 AS i2, 4::int4 AS i4, 8::int8 AS i8, \
+             0.5::float4 AS f4, 0.25::float8 AS f8, \
+             12345678901234567890.123456789::numeric AS n, 'text'::text AS t, \
+             'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'::uuid AS u, \
+             '{\"a\": [1]}'::json AS j, '{\"b\": null}'::jsonb AS jb, \
+             '2024-01-02'::date AS d, '03:04:05.25'::time AS tm, \
+             '2024-01-02 03:04:05'::timestamp AS ts, \
+             '2024-01-02 03:04:05.000001+02'::timestamptz AS tz"
+        )
+        .await,
+        r#"{"b":true,"i2":2,"i4":4,"i8":8,"f4":0.5,"f8":0.25,"#.to_string()
+            + r#""n":12345678901234567890.123456789000,"t":"text","#
+            + r#""u":"a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11","j":{"a":[1]},"jb":{"b":null},"#
+            + r#""d":"2024-01-02","tm":"03:04:05.250000","ts":"2024-01-02T03:04:05","#
+            + r#""tz":"2024-01-02T01:04:05.000001Z","missing":null}"#
+    );
+    assert_eq!(
+        projected(
+            &pool,
+            &["i2", "n", "u", "jb", "tz"],
+            "SELECT NULL::int2 AS i2, NULL::numeric AS n, NULL::uuid AS u, \
+             NULL::jsonb AS jb, NULL::timestamptz AS tz"
+        )
+        .await,
+        r#"{"i2":null,"n":null,"u":null,"jb":null,"tz":null}"#
+    );
+}
@@ -0,0 +1,236 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx_actix_streaming::*;
+
+#[derive(Serialize)]
+struct Contact {
+    email: String,
+    phone: String,
+}
+
+#[derive(Serialize)]
+struct Customer {
+    id: i64,
+    name: String,
+    contact: Contact,
+    ssn: Option<String>,
+}
+
+fn customer() -> Customer {
+    Customer {
+        id: 7,
+        name: "Alice Example".to_string(),
+        contact: Contact {
+            email: "alice@example.com".to_string(),
+            phone: "555-0100".to_string(),
+        },
+        ssn: Some("078-05-1120".to_string()),
+    }
+}
+
+fn to_string<F: FnOnce(&mut Vec<u8>) -> Result<(), serde_json::Error>>(f: F) -> String {
+    let mut out = Vec::new();
+    f(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+const CUSTOMER_COLS: &[&str] = &["id", "name", "contact", "ssn"];
+
+#[test]
+fn positional() {
+    let text = to_string(|out| to_writer_positional(out, CUSTOMER_COLS, &customer()));
+    let value: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value[0], 7);
+    assert_eq!(value[2]["phone"], "555-0100");
+    assert!(to_fields(&42).is_err());
+    assert!(to_writer_positional(Vec::new(), CUSTOMER_COLS, &42).is_err());
+}
+
+#[derive(Serialize)]
+struct Sparse {
+    id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    tag: String,
+    name: String,
+}
+
+#[test]
+fn positional_skipped_fields() {
+    let cols = ColumnInfo::from_fields(&Sparse {
+        id: 1,
+        note: None,
+        tag: String::new(),
+        name: "a".to_string(),
+    })
+    .unwrap();
+    let names: Vec<_> = cols.iter().map(|col| col.name.as_str()).collect();
+    assert_eq!(names, ["id", "note", "tag", "name"]);
+    let sparse = Sparse {
+        id: 2,
+        note: None,
+        tag: "t".to_string(),
+        name: "b".to_string(),
+    };
+    assert_eq!(
+        to_string(|out| to_writer_positional(out, &cols, &sparse)),
+        r#"[2,null,"t","b"]"#
+    );
+    // the columns are matched by name, in column order.
+    assert_eq!(
+        to_string(|out| to_writer_positional(out, &["name", "missing", "id"], &sparse)),
+        r#"["b",null,2]"#
+    );
+    let map = json!({"name": "c", "id": 3, "extra": true});
+    assert_eq!(
+        to_string(|out| to_writer_positional(out, &["id", "note", "name"], &map)),
+        r#"[3,null,"c"]"#
+    );
+    assert_eq!(
+        to_string(|out| to_writer_positional(out, &[] as &[&str], &map)),
+        "[]"
+    );
+}
+
+#[test]
+fn projection() {
+    let allowlist = FieldAllowlist::new()
+        .field("id")
+        .rename("name", "full_name")
+        .field("contact");
+    let projection = allowlist.project(Some("full_name,id")).unwrap();
+    assert_eq!(
+        to_string(|out| projection.to_writer(out, &customer())),
+        r#"{"full_name":"Alice Example","id":7}"#
+    );
+    let all = allowlist.project(None).unwrap();
+    assert_eq!(
+        all.names().collect::<Vec<_>>(),
+        vec!["id", "full_name", "contact"]
+    );
+    assert_eq!(
+        allowlist.project(Some("id,ssn")).unwrap_err(),
+        ProjectionError::UnknownField("ssn".to_string())
+    );
+    assert_eq!(
+        allowlist.project(Some("name")).unwrap_err(),
+        ProjectionError::UnknownField("name".to_string())
+    );
+    assert_eq!(
+        allowlist.project(Some("id,id")).unwrap_err(),
+        ProjectionError::DuplicateField("id".to_string())
+    );
+}
+
+#[test]
+fn projection_of_map() {
+    let projection = FieldAllowlist::new()
+        .field("b")
+        .field("a")
+        .project(None)
+        .unwrap();
+    assert_eq!(
+        to_string(|out| projection.to_writer(out, &json!({"a": 1, "c": 3}))),
+        r#"{"b":null,"a":1}"#
+    );
+}
+
+// fails if it is serialized, to show that hidden fields are not.
+struct Secret;
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+        Err(serde::ser::Error::custom("serialized a hidden field"))
+    }
+}
+
+#[derive(Serialize)]
+struct Account {
+    id: i64,
+    password: Secret,
+    owner: Owner,
+}
+
+#[derive(Serialize)]
+struct Owner {
+    name: String,
+    token: Secret,
+}
+
+fn account() -> Account {
+    Account {
+        id: 7,
+        password: Secret,
+        owner: Owner {
+            name: "Alice".to_string(),
+            token: Secret,
+        },
+    }
+}
+
+#[test]
+fn filtered_struct_skips_hidden_fields() {
+    let fields = to_fields_filtered(&account(), &|name| name == "id").unwrap();
+    assert_eq!(fields, vec![("id".to_string(), json!(7))]);
+    let projection = FieldAllowlist::new().field("id").project(None).unwrap();
+    assert_eq!(
+        to_string(|out| projection.to_writer(out, &account())),
+        r#"{"id":7}"#
+    );
+}
+
+#[test]
+fn filtered_map_skips_hidden_fields() {
+    let mut map = std::collections::BTreeMap::new();
+    map.insert("id", None);
+    map.insert("password", Some(Secret));
+    let fields = to_fields_filtered(&map, &|name| name == "id").unwrap();
+    assert_eq!(fields, vec![("id".to_string(), Value::Null)]);
+}
+
+#[test]
+fn filtered_nested_value_is_not_serialized() {
+    // the nested owner is hidden as a whole, including its token.
+    let fields = to_fields_filtered(&account(), &|name| name != "password" && name != "owner");
+    assert_eq!(fields.unwrap(), vec![("id".to_string(), json!(7))]);
+    // a kept field is serialized whole, so it must not contain secrets.
+    assert!(to_fields_filtered(&account(), &|name| name == "owner").is_err());
+}
+
+#[test]
+fn redaction() {
+    let policy = RedactionPolicy::new()
+        .hmac_key("secret")
+        .rule("ssn", Redaction::Drop)
+        .rule("email", Redaction::Hash)
+        .rule("contact.phone", Redaction::Mask(4))
+        .rule("name", Redaction::Truncate(5));
+    let text = to_string(|out| policy.to_writer(out, &customer()));
+    assert!(
+        text.starts_with(r#"{"id":7,"name":"Alice","contact":{"#),
+        "{}",
+        text
+    );
+    let value: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value["contact"]["phone"], "****0100");
+    assert_eq!(value["contact"]["email"].as_str().unwrap().len(), 64);
+    assert!(value.get("ssn").is_none());
+}
+
+#[test]
+fn redaction_by_role() {
+    let policies = RedactionPolicies::new(RedactionPolicy::new().rule("email", Redaction::Null))
+        .role("admin", RedactionPolicy::new());
+    let value = policies.for_role("guest").apply(&customer()).unwrap();
+    assert_eq!(value["contact"]["email"], Value::Null);
+    let value = policies.for_role("admin").apply(&customer()).unwrap();
+    assert_eq!(value["contact"]["email"], "alice@example.com");
+}
+
+#[test]
+fn hash_without_key_is_null() {
+    let policy = RedactionPolicy::new().rule("email", Redaction::Hash);
+    let value = policy.apply(&customer()).unwrap();
+    assert_eq!(value["contact"]["email"], Value::Null);
+}
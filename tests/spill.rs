@@ -0,0 +1,104 @@
+use bytes::Bytes;
+use futures::{
+    executor::block_on,
+    future::join,
+    stream,
+    task::{noop_waker, Context},
+    Future, Stream, StreamExt, TryStreamExt,
+};
+use sqlx_actix_streaming::*;
+use std::{io, pin::Pin};
+
+fn chunks(n: usize) -> impl Stream<Item = Result<Bytes, Error>> + Unpin {
+    stream::iter((0..n).map(|i| Ok(Bytes::from(format!("chunk {:03};", i)))))
+}
+
+fn expected(n: usize) -> String {
+    (0..n).map(|i| format!("chunk {:03};", i)).collect()
+}
+
+fn read_all<E>(s: SpillStream<E>) -> String
+where
+    E: From<io::Error> + std::fmt::Debug,
+{
+    let body: Vec<u8> = block_on(s.map_ok(|chunk| chunk.to_vec()).try_concat()).unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+#[test]
+fn spill_in_memory() {
+    let (reader, driver) = spill(chunks(10), 1 << 20);
+    block_on(driver);
+    let stats = reader.stats();
+    assert!(stats.drained);
+    assert_eq!(stats.spilled_bytes, 0);
+    assert_eq!(stats.peak_memory, 100);
+    assert_eq!(read_all(reader), expected(10));
+}
+
+#[test]
+fn spill_to_file() {
+    // the query is drained before the client reads anything.
+    let (reader, driver) = spill(chunks(100), 50);
+    block_on(driver);
+    let stats = reader.stats();
+    assert!(stats.drained);
+    assert_eq!(stats.peak_memory, 50);
+    assert_eq!(stats.spilled_chunks, 95);
+    assert_eq!(stats.spilled_bytes, 950);
+    assert_eq!(read_all(reader), expected(100));
+}
+
+#[test]
+fn spill_unpin_not_required() {
+    // the async block makes the inner stream !Unpin.
+    let inner = stream::unfold(0, |i| async move {
+        (i < 100).then(|| {
+            (
+                Ok::<_, Error>(Bytes::from(format!("chunk {:03};", i))),
+                i + 1,
+            )
+        })
+    });
+    let (reader, driver) = spill(inner, 50);
+    block_on(driver);
+    assert_eq!(read_all(reader), expected(100));
+}
+
+#[test]
+fn spill_concurrent_reader() {
+    let (reader, driver) = spill(chunks(1000), 100);
+    let body = reader.map_ok(|chunk| chunk.to_vec()).try_concat();
+    let ((), body) = block_on(join(driver, body));
+    assert_eq!(String::from_utf8(body.unwrap()).unwrap(), expected(1000));
+}
+
+#[test]
+fn spill_error_after_chunks() {
+    let inner = chunks(3).chain(stream::iter(vec![Err(Error::Io(io::Error::other(
+        "failed",
+    )))]));
+    let (mut reader, driver) = spill(inner, 15);
+    block_on(driver);
+    let mut body = String::new();
+    let error = loop {
+        match block_on(reader.next()) {
+            Some(Ok(chunk)) => body.push_str(std::str::from_utf8(&chunk).unwrap()),
+            Some(Err(e)) => break e,
+            None => panic!("expected an error"),
+        }
+    };
+    assert_eq!(body, expected(3));
+    assert!(matches!(error, Error::Io(_)));
+    assert!(block_on(reader.next()).is_none());
+}
+
+#[test]
+fn spill_reader_dropped() {
+    let (reader, mut driver) = spill(chunks(10).chain(stream::pending()), 1 << 20);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(Pin::new(&mut driver).poll(&mut cx).is_pending());
+    drop(reader);
+    assert!(Pin::new(&mut driver).poll(&mut cx).is_ready());
+}
@@ -0,0 +1,595 @@
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{sqlite::*, Executor, FromRow};
+use sqlx_actix_streaming::*;
+
+#[derive(Serialize, FromRow, Debug)]
+struct Widget {
+    id: i64,
+    serial: i64,
+    name: String,
+    description: String,
+}
+
+async fn pool(rows: i64) -> SqlitePool {
+    // each connection to :memory: is a separate database.
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    pool.execute(
+        "CREATE TABLE widgets (
+             id INTEGER PRIMARY KEY NOT NULL,
+             serial INTEGER NOT NULL,
+             name TEXT NOT NULL,
+             description TEXT NOT NULL
+         )",
+    )
+    .await
+    .unwrap();
+    for id in 1..=rows {
+        sqlx::query("INSERT INTO widgets VALUES (?, ?, ?, ?)")
+            .bind(id)
+            .bind(10000 + id)
+            .bind(format!("widget {}", id))
+            .bind(format!("description of widget {}", id))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    pool
+}
+
+async fn collect_json<S, B, E>(s: S) -> Value
+where
+    S: futures::Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Debug,
+{
+    let mut out = Vec::new();
+    for chunk in s.collect::<Vec<_>>().await {
+        out.extend_from_slice(chunk.unwrap().as_ref());
+    }
+    serde_json::from_slice(&out)
+        .unwrap_or_else(|e| panic!("invalid json: {}: {}", e, String::from_utf8_lossy(&out)))
+}
+
+fn write_widget(buf: &mut BytesWriter, widget: &Widget) -> Result<(), Error> {
+    Ok(serde_json::to_writer(buf, widget)?)
+}
+
+#[tokio::test]
+async fn self_ref_stream_owns_pool_and_params() {
+    let pool = pool(10).await;
+    let s = SelfRefStream::build((pool, 3_i64), |(pool, limit)| {
+        sqlx::query_as::<_, Widget>("SELECT * FROM widgets ORDER BY id LIMIT ?")
+            .bind(limit)
+            .fetch(pool)
+    });
+    let widgets: Vec<Widget> = s.try_collect().await.unwrap();
+    assert_eq!(widgets.len(), 3);
+    assert_eq!(widgets[2].name, "widget 3");
+}
+
+#[tokio::test]
+async fn byte_stream_of_rows() {
+    for &rows in &[0, 1, 500] {
+        let pool = pool(rows).await;
+        let s = ByteStream::new(
+            SelfRefStream::build(pool, |pool| {
+                sqlx::query_as::<_, Widget>("SELECT * FROM widgets ORDER BY id").fetch(pool)
+            }),
+            write_widget,
+        );
+        let value = collect_json(s).await;
+        assert_eq!(value.as_array().unwrap().len(), rows as usize);
+    }
+}
+
+#[tokio::test]
+async fn query_error() {
+    let pool = pool(1).await;
+    let s = ByteStream::new(
+        SelfRefStream::build(pool, |pool| {
+            sqlx::query_as::<_, Widget>("SELECT * FROM no_such_table").fetch(pool)
+        }),
+        write_widget,
+    );
+    let chunks = s.collect::<Vec<_>>().await;
+    assert!(chunks
+        .iter()
+        .any(|chunk| matches!(chunk, Err(Error::Sqlx(_)))));
+}
+
+#[tokio::test]
+async fn row_columns() {
+    let pool = pool(2).await;
+    let s = ByteStream::new(
+        SelfRefStream::build(pool, |pool| {
+            sqlx::query("SELECT id, name FROM widgets ORDER BY id").fetch(pool)
+        }),
+        |buf: &mut BytesWriter, row: &SqliteRow| {
+            use sqlx::Row;
+            let id: i64 = row.try_get("id")?;
+            let name: String = row.try_get("name")?;
+            Ok::<_, Error>(to_writer_positional(
+                buf,
+                &["id", "name"],
+                &json!({"name": name, "id": id}),
+            )?)
+        },
+    )
+    .row_columns();
+    let value = collect_json(s).await;
+    assert_eq!(value["cols"][0]["name"], "id");
+    assert_eq!(value["cols"][0]["type"], "INTEGER");
+    assert_eq!(value["cols"][0]["json_type"], "number");
+    assert_eq!(value["cols"][1]["type"], "TEXT");
+    assert_eq!(value["cols"][1]["json_type"], "string");
+    assert_eq!(value["rows"], json!([[1, "widget 1"], [2, "widget 2"]]));
+}
+
+#[tokio::test]
+async fn projection_of_rows() {
+    let pool = pool(2).await;
+    let projection = FieldAllowlist::new()
+        .field("id")
+        .rename("name", "title")
+        .field("weight")
+        .project(Some("title,id,weight"))
+        .unwrap();
+    let row = sqlx::query("SELECT * FROM widgets WHERE id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let mut out = Vec::new();
+    projection.row_to_writer(&mut out, &row).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        r#"{"title":"widget 1","id":1,"weight":null}"#
+    );
+    let s = ByteStream::new(
+        SelfRefStream::build(pool, |pool| {
+            sqlx::query("SELECT * FROM widgets ORDER BY id").fetch(pool)
+        }),
+        move |buf: &mut BytesWriter, row: &SqliteRow| projection.row_to_writer(buf, row),
+    );
+    assert_eq!(
+        collect_json(s).await,
+        json!([
+            {"title": "widget 1", "id": 1, "weight": null},
+            {"title": "widget 2", "id": 2, "weight": null}
+        ])
+    );
+}
+
+#[tokio::test]
+async fn describe_columns() {
+    let pool = pool(0).await;
+    let describe = (&pool)
+        .describe("SELECT id, name FROM widgets")
+        .await
+        .unwrap();
+    let cols = ColumnInfo::from_describe(&describe);
+    assert_eq!(cols.len(), 2);
+    assert_eq!(cols[0].name, "id");
+    assert_eq!(cols[0].nullable, Some(false));
+    let prefix = columns_prefix(&cols).unwrap();
+    let text = format!("{}]}}", prefix);
+    let value: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value["cols"][1]["name"], "name");
+    assert_eq!(value["rows"], json!([]));
+}
+
+// count the widgets, failing if there are none, before streaming them.
+async fn checked_widgets(
+    pool: SqlitePool,
+) -> Result<SelfRefStream<SqlitePool, Widget, sqlx::Error>, sqlx::Error> {
+    SelfRefStream::try_build_async(pool, |pool| {
+        async move {
+            let count: i64 = sqlx::query_scalar("SELECT count(*) FROM widgets")
+                .fetch_one(pool)
+                .await?;
+            if count == 0 {
+                return Err(sqlx::Error::RowNotFound);
+            }
+            Ok(sqlx::query_as::<_, Widget>("SELECT * FROM widgets ORDER BY id").fetch(pool))
+        }
+        .boxed_local()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn try_build_async_awaits_setup() {
+    let s = checked_widgets(pool(4).await).await.unwrap();
+    let widgets: Vec<Widget> = s.try_collect().await.unwrap();
+    assert_eq!(widgets.len(), 4);
+}
+
+#[tokio::test]
+async fn try_build_async_returns_setup_error() {
+    let result = checked_widgets(pool(0).await).await;
+    assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+}
+
+// stream the widgets in a transaction which inserts one more.
+async fn widgets_in_transaction(
+    pool: &SqlitePool,
+) -> SelfRefStreamMut<sqlx::Transaction<'static, Sqlite>, Widget, sqlx::Error> {
+    let mut tx = pool.begin().await.unwrap();
+    sqlx::query("INSERT INTO widgets VALUES (100, 100, 'new', 'inserted in a transaction')")
+        .execute(&mut tx)
+        .await
+        .unwrap();
+    SelfRefStreamMut::build(tx, |tx| {
+        sqlx::query_as::<_, Widget>("SELECT * FROM widgets ORDER BY id").fetch(tx)
+    })
+}
+
+async fn count(pool: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT count(*) FROM widgets")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn on_end_commits_after_all_rows() {
+    let pool = pool(3).await;
+    let s = widgets_in_transaction(&pool)
+        .await
+        .on_end(|tx| tx.commit().boxed());
+    let widgets: Vec<Widget> = s.try_collect().await.unwrap();
+    assert_eq!(widgets.len(), 4);
+    assert_eq!(count(&pool).await, 4);
+}
+
+#[tokio::test]
+async fn on_end_is_skipped_when_dropped_early() {
+    let pool = pool(3).await;
+    let mut s = widgets_in_transaction(&pool)
+        .await
+        .on_end(|tx| tx.commit().boxed());
+    assert!(s.next().await.is_some());
+    drop(s);
+    assert_eq!(count(&pool).await, 3);
+}
+
+#[tokio::test]
+async fn into_args_after_partial_read() {
+    let pool = pool(5).await;
+    let conn = pool.acquire().await.unwrap();
+    let mut s = SelfRefStreamMut::build(conn, |conn| {
+        sqlx::query_as::<_, Widget>("SELECT * FROM widgets ORDER BY id").fetch(conn)
+    });
+    assert_eq!(s.next().await.unwrap().unwrap().id, 1);
+    assert_eq!(s.next().await.unwrap().unwrap().id, 2);
+    let mut conn = s.into_args();
+    let n: i64 = sqlx::query_scalar("SELECT count(*) FROM widgets")
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(n, 5);
+}
+
+fn widgets_stream(
+    pool: SqlitePool,
+    sql: &'static str,
+) -> SelfRefStream<(SqlitePool, &'static str), Widget, sqlx::Error> {
+    SelfRefStream::build((pool, sql), |(pool, sql)| {
+        sqlx::query_as::<_, Widget>(sql).fetch(pool)
+    })
+}
+
+#[tokio::test]
+async fn peeked_stream_replays_first_row() {
+    let rows = PeekedStream::new(widgets_stream(
+        pool(3).await,
+        "SELECT * FROM widgets ORDER BY id",
+    ))
+    .await
+    .unwrap();
+    assert!(!rows.is_empty());
+    assert_eq!(rows.first().unwrap().id, 1);
+    let s = ByteStream::new(rows, write_widget);
+    assert_eq!(collect_json(s).await.as_array().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn peeked_stream_returns_query_error() {
+    let result = PeekedStream::new(widgets_stream(pool(3).await, "SELECT * FROM gadgets")).await;
+    assert!(matches!(result, Err(sqlx::Error::Database(_))));
+}
+
+#[tokio::test]
+async fn peeked_stream_empty() {
+    let rows = PeekedStream::new(widgets_stream(pool(0).await, "SELECT * FROM widgets"))
+        .await
+        .unwrap();
+    assert!(rows.is_empty());
+    assert!(matches!(rows.non_empty(|| "not found"), Err("not found")));
+}
+
+// a query borrowing the args, mapped by an async closure so that the
+// stream is not Unpin.
+fn widgets_after(
+    (pool, after): &(SqlitePool, i64),
+) -> impl futures::Stream<Item = Result<Widget, sqlx::Error>> + '_ {
+    sqlx::query_as::<_, Widget>("SELECT * FROM widgets WHERE id > ? ORDER BY id")
+        .bind(*after)
+        .fetch(pool)
+        .and_then(|widget| async move { Ok(widget) })
+}
+
+#[tokio::test]
+async fn unboxed_self_ref_stream_of_query() {
+    let pool = pool(3).await;
+    let s = UnboxedSelfRefStream::build((pool.clone(), 1), widgets_after);
+    assert_eq!(s.args().1, 1);
+    let s = ByteStream::new(s, write_widget);
+    let ids: Vec<_> = collect_json(s)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|w| w["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids, [2, 3]);
+    // it is not Unpin, so it is boxed to be peeked.
+    let rows = PeekedStream::new(Box::pin(UnboxedSelfRefStream::build(
+        (pool, 0),
+        widgets_after,
+    )))
+    .await
+    .unwrap();
+    assert_eq!(rows.first().unwrap().id, 1);
+    let s = ByteStream::new(rows, write_widget);
+    assert_eq!(collect_json(s).await.as_array().unwrap().len(), 3);
+}
+
+// export the widgets, resumed at an offset after a checkpoint, as the
+// server does for a Range request.
+fn export(
+    pool: SqlitePool,
+    resume: Option<(&Checkpoint, usize)>,
+    checkpoints: &Checkpoints,
+) -> impl futures::Stream<Item = Result<bytes::Bytes, Error>> {
+    let after: i64 = resume
+        .and_then(|(c, _)| c.key.as_ref())
+        .map_or(0, |key| key.parse().unwrap());
+    let s = ByteStream::with_size(
+        SelfRefStream::build((pool, after), |(pool, after)| {
+            sqlx::query_as::<_, Widget>("SELECT * FROM widgets WHERE id > ? ORDER BY id")
+                .bind(after)
+                .fetch(pool)
+        }),
+        write_widget,
+        128,
+    )
+    .envelope(&json!({"version": 1}), |stats, _: Option<&Widget>| {
+        stats.item_count
+    })
+    .unwrap()
+    .checkpoints(|widget| widget.id.to_string(), checkpoints);
+    match resume {
+        Some((checkpoint, offset)) => s.resume_at(checkpoint, offset),
+        None => s,
+    }
+}
+
+async fn concat<S: futures::Stream<Item = Result<bytes::Bytes, Error>>>(s: S) -> Vec<u8> {
+    s.try_fold(Vec::new(), |mut out, chunk| async move {
+        out.extend_from_slice(&chunk);
+        Ok(out)
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn resume_by_range_is_byte_identical() {
+    let pool = pool(50).await;
+    let single_pass = concat(export(pool.clone(), None, &Checkpoints::new())).await;
+    let doc: Value = serde_json::from_slice(&single_pass).unwrap();
+    assert_eq!(doc["summary"], 50);
+    // the server keeps the checkpoints of the export.
+    let checkpoints = Checkpoints::new();
+    for (interrupt_after, broken) in [(1, 0), (1, 1000), (2, 5), (3, 100), (4, 1)] {
+        // the client keeps what it received, up to a broken chunk.
+        let mut output =
+            concat(export(pool.clone(), None, &checkpoints).take(interrupt_after)).await;
+        output.truncate(output.len().saturating_sub(broken));
+        // it asks for the rest, knowing only how much it has.
+        let range = format!("bytes={}-", output.len());
+        let offset = range_start(&range).unwrap();
+        let checkpoint = checkpoints.before(offset).unwrap_or_default();
+        assert!(checkpoint.offset <= offset);
+        output.extend(
+            concat(export(
+                pool.clone(),
+                Some((&checkpoint, offset)),
+                &checkpoints,
+            ))
+            .await,
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            String::from_utf8(single_pass.clone()).unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn resume_token_in_trailers() {
+    let pool = pool(50).await;
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let checkpoints = Checkpoints::new();
+    let received = concat(
+        ByteStream::with_size(
+            widgets_stream(pool, "SELECT * FROM widgets ORDER BY id"),
+            write_widget,
+            128,
+        )
+        .checkpoints(|widget| widget.id.to_string(), &checkpoints)
+        .http_trailers(sender)
+        .take(3),
+    )
+    .await;
+    let trailers = receiver.await.unwrap();
+    assert_eq!(trailers.status, StreamStatus::Error);
+    let checkpoint: Checkpoint = trailers.resume_token.unwrap().parse().unwrap();
+    assert_eq!(checkpoint, checkpoints.latest().unwrap());
+    assert_eq!(checkpoint.offset, received.len());
+    assert_eq!(checkpoint.key, Some(checkpoint.item_count.to_string()));
+}
+
+#[test]
+fn resume_token() {
+    let checkpoint: Checkpoint = r#"42:3:"a:b""#.parse().unwrap();
+    assert_eq!(checkpoint.offset, 42);
+    assert_eq!(checkpoint.item_count, 3);
+    assert_eq!(checkpoint.key.as_deref(), Some("a:b"));
+    assert_eq!(checkpoint.to_string(), r#"42:3:"a:b""#);
+    // the key is escaped.
+    let checkpoint = Checkpoint {
+        offset: 1,
+        item_count: 1,
+        key: Some("say \"hi\"\\\n".to_string()),
+    };
+    let token = checkpoint.to_string();
+    assert!(!token.contains('\n'));
+    assert_eq!(token.parse::<Checkpoint>().unwrap(), checkpoint);
+    assert_eq!("7:0".parse::<Checkpoint>().unwrap().key, None);
+    assert!("7".parse::<Checkpoint>().is_err());
+    assert!("x:1".parse::<Checkpoint>().is_err());
+    assert!("7:1:unquoted".parse::<Checkpoint>().is_err());
+    assert_eq!(range_start("bytes=1234-"), Some(1234));
+    assert_eq!(range_start("bytes=0-99"), None);
+}
+
+// a shard having the widgets with the given ids.
+async fn shard(ids: &[i64]) -> SqlitePool {
+    let pool = pool(0).await;
+    for &id in ids {
+        sqlx::query("INSERT INTO widgets VALUES (?, ?, ?, ?)")
+            .bind(id)
+            .bind(10000 + id)
+            .bind(format!("widget {}", id))
+            .bind(format!("description of widget {}", id))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    pool
+}
+
+async fn shards() -> Vec<SelfRefStream<(SqlitePool, &'static str), Widget, sqlx::Error>> {
+    vec![
+        widgets_stream(shard(&[1, 4, 7]).await, "SELECT * FROM widgets ORDER BY id"),
+        widgets_stream(shard(&[2, 5]).await, "SELECT * FROM widgets ORDER BY id"),
+        widgets_stream(
+            shard(&[3, 6, 8, 9]).await,
+            "SELECT * FROM widgets ORDER BY id",
+        ),
+    ]
+}
+
+fn ids(doc: &Value) -> Vec<i64> {
+    doc.as_array()
+        .unwrap()
+        .iter()
+        .map(|w| w["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn merge_ordered() {
+    let merged = MergeStream::ordered(shards().await, |w: &Widget| w.id);
+    let doc = collect_json(ByteStream::new(merged, write_widget)).await;
+    assert_eq!(ids(&doc), (1..=9).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn merge_unordered() {
+    let merged = MergeStream::unordered(shards().await);
+    let mut ids = ids(&collect_json(ByteStream::new(merged, write_widget)).await);
+    ids.sort_unstable();
+    assert_eq!(ids, (1..=9).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn merge_fail_fast() {
+    let mut shards = shards().await;
+    shards[1] = widgets_stream(shard(&[]).await, "SELECT * FROM gadgets");
+    let merged = MergeStream::ordered(shards, |w: &Widget| w.id);
+    let result: Result<Vec<_>, _> = ByteStream::new(merged, write_widget).try_collect().await;
+    assert!(matches!(result, Err(Error::Sqlx(_))));
+}
+
+#[tokio::test]
+async fn merge_reports_shard_errors() {
+    let mut shards = shards().await;
+    shards[1] = widgets_stream(shard(&[]).await, "SELECT * FROM gadgets");
+    let errors = ShardErrors::new();
+    let merged = MergeStream::ordered(shards, |w: &Widget| w.id)
+        .policy(ShardErrorPolicy::Report(errors.clone()));
+    let s = ByteStream::new(merged, write_widget)
+        .envelope(&(), move |stats, _: Option<&Widget>| {
+            json!({"count": stats.item_count, "errors": errors.to_vec()})
+        })
+        .unwrap();
+    let doc = collect_json(s).await;
+    assert_eq!(ids(&doc["data"]), vec![1, 3, 4, 6, 7, 8, 9]);
+    assert_eq!(doc["summary"]["count"], 7);
+    assert_eq!(doc["summary"]["errors"][0]["shard"], 1);
+    assert!(doc["summary"]["errors"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("gadgets"));
+}
+
+#[derive(Serialize)]
+struct Page {
+    offset: i64,
+    limit: i64,
+}
+
+async fn version(pool: &SqlitePool, if_none_match: &str) -> Result<Conditional, Error> {
+    let page = Page {
+        offset: 0,
+        limit: 10,
+    };
+    conditional(Some(if_none_match), &page, async {
+        let max_id: i64 = sqlx::query_scalar("SELECT max(id) FROM widgets")
+            .fetch_one(pool)
+            .await?;
+        Ok::<_, Error>(max_id)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn conditional_etag() {
+    let pool = pool(3).await;
+    let etag = match version(&pool, "W/\"0123\"").await.unwrap() {
+        Conditional::Modified(etag) => etag,
+        other => panic!("expected modified: {:?}", other),
+    };
+    assert!(etag.as_str().starts_with("W/\""));
+    // the same version and params give the same etag.
+    assert_eq!(
+        version(&pool, etag.as_str()).await.unwrap(),
+        Conditional::NotModified(etag.clone())
+    );
+    sqlx::query("INSERT INTO widgets VALUES (4, 4, 'widget 4', '')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    match version(&pool, etag.as_str()).await.unwrap() {
+        Conditional::Modified(changed) => assert_ne!(changed, etag),
+        other => panic!("expected modified: {:?}", other),
+    }
+}
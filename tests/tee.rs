@@ -0,0 +1,181 @@
+use bytes::Bytes;
+use futures::{executor::block_on, stream, Stream, StreamExt, TryStreamExt};
+use sqlx_actix_streaming::*;
+use std::{io, time::Duration};
+
+fn chunks(n: usize) -> impl Stream<Item = Result<Bytes, Error>> + Unpin {
+    stream::iter((0..n).map(|i| Ok(Bytes::from(format!("chunk {:03};", i)))))
+}
+
+fn failing() -> impl Stream<Item = Result<Bytes, Error>> + Unpin {
+    chunks(2).chain(stream::iter(vec![Err(Error::Io(io::Error::other(
+        "failed",
+    )))]))
+}
+
+fn body<E: std::fmt::Debug>(s: impl Stream<Item = Result<Bytes, E>>) -> String {
+    let body: Vec<u8> = block_on(s.map_ok(|chunk| chunk.to_vec()).try_concat()).unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+fn cached(cache: &dyn ArtifactCache, key: &str) -> Option<(String, String)> {
+    cache
+        .get(key)
+        .map(|cached| (cached.etag.clone(), body(cached.stream())))
+}
+
+fn commits_on_completion(cache: &dyn ArtifactCache) {
+    assert_eq!(
+        body(Tee::new(chunks(3), cache.sink("report"))),
+        body(chunks(3))
+    );
+    let (etag, content) = cached(cache, "report").unwrap();
+    assert_eq!(content, body(chunks(3)));
+    assert!(etag.starts_with('"') && etag.len() == 66);
+    assert!(cache.get("other").is_none());
+}
+
+fn aborts_on_error_or_drop(cache: &dyn ArtifactCache) {
+    // the error is written into the document, which still ends normally.
+    let rows = stream::iter(vec![Ok(1), Err(io::Error::other("failed"))]);
+    let s = ByteStream::new(rows, |buf: &mut BytesWriter, n: &i32| {
+        Ok::<_, Error>(serde_json::to_writer(buf, n)?)
+    })
+    .on_error(|buf, _| {
+        buf.write_all(br#","error"]"#)?;
+        Ok(())
+    })
+    .tee(cache.sink("on_error"));
+    assert_eq!(body(s), r#"[1,"error"]"#);
+    assert!(cache.get("on_error").is_none());
+    let mut s = Tee::new(failing(), cache.sink("failed"));
+    while let Some(Ok(_)) = block_on(s.next()) {}
+    drop(s);
+    assert!(cache.get("failed").is_none());
+    let mut s = Tee::new(chunks(3), cache.sink("dropped"));
+    block_on(s.next());
+    drop(s);
+    assert!(cache.get("dropped").is_none());
+}
+
+#[test]
+fn memory_cache() {
+    let cache = MemoryCache::new(1 << 20);
+    commits_on_completion(&cache);
+    aborts_on_error_or_drop(&cache);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn memory_cache_evicts_lru() {
+    let cache = MemoryCache::new(100);
+    body(Tee::new(chunks(4), cache.sink("a")));
+    body(Tee::new(chunks(4), cache.sink("b")));
+    assert!(cache.get("a").is_some());
+    body(Tee::new(chunks(4), cache.sink("c")));
+    assert!(cache.get("b").is_none());
+    assert!(cache.get("a").is_some() && cache.get("c").is_some());
+    assert_eq!(cache.size(), 80);
+    // too large to cache.
+    body(Tee::new(chunks(11), cache.sink("d")));
+    assert!(cache.get("d").is_none());
+}
+
+#[test]
+fn file_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FileCache::new(dir.path());
+    commits_on_completion(&cache);
+    aborts_on_error_or_drop(&cache);
+    // only the committed stream remains, with its etag in one file.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn memory_cache_expires() {
+    let cache = MemoryCache::new(1 << 20).max_age(Duration::from_millis(50));
+    body(Tee::new(chunks(3), cache.sink("report")));
+    assert!(cache.get("report").is_some());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(cache.get("report").is_none());
+    assert_eq!(cache.size(), 0);
+}
+
+fn files(dir: &tempfile::TempDir) -> usize {
+    std::fs::read_dir(dir.path()).unwrap().count()
+}
+
+#[test]
+fn file_cache_removes_tmp_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FileCache::new(dir.path());
+    let mut s = Tee::new(chunks(3), cache.sink("dropped"));
+    block_on(s.next());
+    assert_eq!(files(&dir), 1);
+    drop(s);
+    assert_eq!(files(&dir), 0);
+    // a sink dropped without a commit or an abort.
+    let mut sink = cache.sink("sink");
+    sink.write(&Bytes::from("chunk")).unwrap();
+    drop(sink);
+    assert_eq!(files(&dir), 0);
+}
+
+#[test]
+fn file_cache_expires() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FileCache::new(dir.path()).max_age(Duration::from_millis(50));
+    body(Tee::new(chunks(3), cache.sink("requested")));
+    body(Tee::new(chunks(3), cache.sink("idle")));
+    // as left by a crash.
+    std::fs::write(dir.path().join("crashed.tmp"), "chunk").unwrap();
+    assert!(cache.get("requested").is_some());
+    std::thread::sleep(Duration::from_millis(100));
+    // an expired file is removed when requested, or by cleanup().
+    assert!(cache.get("requested").is_none());
+    assert_eq!(files(&dir), 2);
+    cache.cleanup().unwrap();
+    assert_eq!(files(&dir), 0);
+}
+
+#[test]
+fn file_cache_max_size() {
+    let dir = tempfile::tempdir().unwrap();
+    // each file is a 67 byte header and 40 bytes of content.
+    let cache = FileCache::new(dir.path()).max_size(250);
+    for key in ["a", "b", "c"] {
+        body(Tee::new(chunks(4), cache.sink(key)));
+        // distinct modification times.
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(cache.get("a").is_none());
+    assert!(cache.get("b").is_some() && cache.get("c").is_some());
+    assert_eq!(files(&dir), 2);
+}
+
+#[test]
+fn etag_matches() {
+    let cache = MemoryCache::new(1 << 20);
+    body(Tee::new(chunks(3), cache.sink("report")));
+    let cached = cache.get("report").unwrap();
+    let etag = cached.etag.clone();
+    assert!(cached.matches(Some(&etag)));
+    assert!(cached.matches(Some(&format!("\"x\", W/{}", etag))));
+    assert!(cached.matches(Some("*")));
+    assert!(!cached.matches(Some("\"x\"")));
+    assert!(!cached.matches(None));
+    match cache.get("report").unwrap().respond(Some(&etag)) {
+        CachedResponse::NotModified { etag: tag } => assert_eq!(tag, etag),
+        CachedResponse::Content { .. } => panic!("expected not modified"),
+    }
+    match cache.get("report").unwrap().respond(Some("\"x\"")) {
+        CachedResponse::Content {
+            etag: tag,
+            body: content,
+        } => {
+            assert_eq!(tag, etag);
+            assert_eq!(body(content), body(chunks(3)));
+        }
+        CachedResponse::NotModified { .. } => panic!("expected the content"),
+    }
+}
@@ -0,0 +1,72 @@
+use bytes::Bytes;
+use futures::{
+    executor::block_on,
+    future, stream,
+    task::{noop_waker, Context, Poll},
+    StreamExt, TryStreamExt,
+};
+use sqlx_actix_streaming::*;
+use std::time::{Duration, Instant};
+
+fn chunks(n: usize, size: usize) -> impl futures::Stream<Item = Result<Bytes, Error>> + Unpin {
+    stream::iter((0..n).map(move |_| Ok(Bytes::from(vec![b'x'; size]))))
+}
+
+#[test]
+fn throttle_rate() {
+    // 3 chunks of 500 bytes at 10k bytes/sec, after a burst of 500.
+    let started = Instant::now();
+    let body: Vec<Bytes> =
+        block_on(Throttle::new(chunks(3, 500), RateLimit::new(10_000, 500)).try_collect()).unwrap();
+    assert_eq!(body.len(), 3);
+    assert!(started.elapsed() >= Duration::from_millis(90));
+}
+
+#[test]
+fn throttle_burst() {
+    // within the burst, every chunk is ready without waiting.
+    let mut s = Throttle::new(chunks(3, 100), RateLimit::new(1_000, 1_000));
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    for _ in 0..3 {
+        assert!(matches!(
+            s.poll_next_unpin(&mut cx),
+            Poll::Ready(Some(Ok(_)))
+        ));
+    }
+    assert!(matches!(s.poll_next_unpin(&mut cx), Poll::Ready(None)));
+    // beyond the burst, the next chunk waits for the tokens.
+    let mut s = Throttle::new(chunks(2, 100), RateLimit::new(1, 50));
+    assert!(matches!(
+        s.poll_next_unpin(&mut cx),
+        Poll::Ready(Some(Ok(_)))
+    ));
+    assert!(s.poll_next_unpin(&mut cx).is_pending());
+}
+
+#[test]
+fn throttle_shared_limit() {
+    // two streams of one client share 10k bytes/sec.
+    let limits = RateLimits::new(10_000, 500);
+    let a = Throttle::new(chunks(3, 500), limits.get("client"));
+    let b = Throttle::new(chunks(3, 500), limits.get("client"));
+    let started = Instant::now();
+    let (a, b) = block_on(future::join(
+        a.try_collect::<Vec<_>>(),
+        b.try_collect::<Vec<_>>(),
+    ));
+    assert_eq!(a.unwrap().len() + b.unwrap().len(), 6);
+    assert!(started.elapsed() >= Duration::from_millis(240));
+}
+
+#[test]
+fn throttle_bytestream() {
+    let s = ByteStream::new(
+        stream::iter((0..100).map(Ok::<_, Error>)),
+        |buf: &mut BytesWriter, n: &i32| Ok::<_, Error>(serde_json::to_writer(buf, n)?),
+    )
+    .throttle(RateLimit::new(1_000_000, 1_000));
+    let body: Vec<u8> = block_on(s.map_ok(|chunk| chunk.to_vec()).try_concat()).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(value.as_array().unwrap().len(), 100);
+}
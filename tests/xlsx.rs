@@ -0,0 +1,190 @@
+use bytes::Bytes;
+use flate2::bufread::DeflateDecoder;
+use futures::{executor::block_on, stream, StreamExt};
+use serde::Serialize;
+use sqlx_actix_streaming::*;
+use std::{convert::TryInto, io::Read};
+
+#[derive(Serialize)]
+struct Widget {
+    id: i64,
+    name: String,
+    in_stock: bool,
+    price: Option<f64>,
+}
+
+fn widgets(n: i64) -> impl futures::Stream<Item = Result<Widget, Error>> + Unpin {
+    stream::iter((1..=n).map(|id| {
+        Ok(Widget {
+            id,
+            name: format!("widget <{}> & co", id),
+            in_stock: id % 2 == 0,
+            price: if id == 1 { None } else { Some(id as f64 / 2.0) },
+        })
+    }))
+}
+
+fn u16_at(b: &[u8], i: usize) -> usize {
+    u16::from_le_bytes(b[i..i + 2].try_into().unwrap()) as usize
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+}
+
+// read the entries of a zip written with data descriptors, checking
+// the descriptors, the central directory and its end record.
+fn unzip(zip: &[u8]) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    let mut offsets = vec![];
+    let mut i = 0;
+    while u32_at(zip, i) == 0x04034b50 {
+        assert_eq!(u16_at(zip, i + 6) & 0x0008, 0x0008);
+        let name_len = u16_at(zip, i + 26);
+        let name = String::from_utf8(zip[i + 30..i + 30 + name_len].to_vec()).unwrap();
+        offsets.push(i);
+        let data = &zip[i + 30 + name_len..];
+        let mut decoder = DeflateDecoder::new(data);
+        let mut content = String::new();
+        decoder.read_to_string(&mut content).unwrap();
+        let compressed = data.len() - decoder.into_inner().len();
+        i += 30 + name_len + compressed;
+        assert_eq!(u32_at(zip, i), 0x08074b50);
+        assert_eq!(u32_at(zip, i + 4), crc32fast::hash(content.as_bytes()));
+        assert_eq!(u32_at(zip, i + 8) as usize, compressed);
+        assert_eq!(u32_at(zip, i + 12) as usize, content.len());
+        i += 16;
+        entries.push((name, content));
+    }
+    let directory = i;
+    for offset in &offsets {
+        assert_eq!(u32_at(zip, i), 0x02014b50);
+        assert_eq!(u32_at(zip, i + 42) as usize, *offset);
+        i += 46 + u16_at(zip, i + 28);
+    }
+    assert_eq!(u32_at(zip, i), 0x06054b50);
+    assert_eq!(u16_at(zip, i + 10), entries.len());
+    assert_eq!(u32_at(zip, i + 12) as usize, i - directory);
+    assert_eq!(u32_at(zip, i + 16) as usize, directory);
+    assert_eq!(zip.len(), i + 22);
+    entries
+}
+
+fn workbook<S: futures::Stream<Item = Result<Bytes, Error>> + Unpin>(
+    s: S,
+) -> (usize, Vec<(String, String)>) {
+    let chunks: Vec<Bytes> = block_on(s.map(Result::unwrap).collect());
+    (chunks.len(), unzip(&chunks.concat()))
+}
+
+fn sheet(entries: &[(String, String)]) -> &str {
+    &entries
+        .iter()
+        .find(|(name, _)| name == "xl/worksheets/sheet1.xml")
+        .unwrap()
+        .1
+}
+
+#[test]
+fn xlsx_rows() {
+    let s = ByteStream::with_size(
+        widgets(200),
+        |buf, widget: &Widget| Ok(write_xlsx_row(buf, widget)?),
+        256,
+    )
+    .field_xlsx();
+    let (chunks, entries) = workbook(s);
+    assert!(chunks > 1);
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "[Content_Types].xml",
+            "_rels/.rels",
+            "xl/workbook.xml",
+            "xl/_rels/workbook.xml.rels",
+            "xl/worksheets/sheet1.xml"
+        ]
+    );
+    let sheet = sheet(&entries);
+    let t = |s: &str| {
+        format!(
+            r#"<c t="inlineStr"><is><t xml:space="preserve">{}</t></is></c>"#,
+            s
+        )
+    };
+    assert!(sheet.contains(&format!(
+        "<sheetData><row>{}{}{}{}</row><row><c><v>1</v></c>{}{}<c/></row>",
+        t("id"),
+        t("name"),
+        t("in_stock"),
+        t("price"),
+        t("widget &lt;1&gt; &amp; co"),
+        r#"<c t="b"><v>0</v></c>"#
+    )));
+    assert!(sheet.contains(r#"<row><c><v>2</v></c>"#));
+    assert!(sheet.contains(r#"<c t="b"><v>1</v></c><c><v>1.0</v></c></row>"#));
+    assert!(sheet.ends_with("</row></sheetData></worksheet>"));
+    assert_eq!(sheet.matches("<row>").count(), 201);
+}
+
+#[test]
+fn xlsx_empty() {
+    let s = ByteStream::new(widgets(0), |buf, widget: &Widget| {
+        Ok(write_xlsx_row(buf, widget)?)
+    })
+    .field_xlsx()
+    .sheet_name("Widgets: [all]");
+    let (_, entries) = workbook(s);
+    assert!(sheet(&entries).ends_with("<sheetData></sheetData></worksheet>"));
+    assert!(entries[2]
+        .1
+        .contains(r#"<sheet name="Widgets_ _all_" sheetId="1""#));
+}
+
+#[test]
+fn xlsx_unpin_not_required() {
+    // the async block makes the inner stream !Unpin.
+    let inner = stream::unfold(widgets(3), |mut widgets| async move {
+        widgets.next().await.map(|widget| (widget, widgets))
+    });
+    let s = ByteStream::new(inner, |buf, widget: &Widget| {
+        Ok(write_xlsx_row(buf, widget)?)
+    })
+    .field_xlsx();
+    let (_, entries) = workbook(Box::pin(s));
+    assert_eq!(sheet(&entries).matches("<row>").count(), 4);
+}
+
+#[test]
+fn xlsx_columns() {
+    let columns = vec![ColumnInfo {
+        name: "Widget ID".to_string(),
+        db_type: Some("INTEGER".to_string()),
+        nullable: Some(false),
+        json_type: JsonType::Number,
+    }];
+    let s = ByteStream::new(widgets(1), |buf, widget: &Widget| {
+        buf.0.extend_from_slice(b"<row>");
+        write_xlsx_cell(buf, &widget.id.into())?;
+        buf.0.extend_from_slice(b"</row>");
+        Ok(())
+    })
+    .xlsx(move |_| Ok(columns.clone()));
+    let (_, entries) = workbook(s);
+    assert!(sheet(&entries).contains(
+        r#"<sheetData><row><c t="inlineStr"><is><t xml:space="preserve">Widget ID</t></is></c></row><row><c><v>1</v></c></row></sheetData>"#
+    ));
+}
+
+#[test]
+fn xlsx_content_disposition() {
+    assert_eq!(
+        content_disposition("widgets.xlsx"),
+        r#"attachment; filename="widgets.xlsx"; filename*=UTF-8''widgets.xlsx"#
+    );
+    assert_eq!(
+        content_disposition("prix \"été\".xlsx"),
+        r#"attachment; filename="prix __t__.xlsx"; filename*=UTF-8''prix%20%22%C3%A9t%C3%A9%22.xlsx"#
+    );
+}